@@ -1,29 +1,66 @@
 use std::convert::Infallible;
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
 
-use memory_backend::reply::{InitResponse, LeaderboardResponse};
-use memory_backend::sse_utils::{broadcast_sse, send_sse};
+use crate::reply::{
+    AdminChangedResponse, AdminGameOverResponse, AdminPlayerResponse, AdminTokenResponse,
+    AnnouncementResponse, BoardResponse, CardResponse, ChatResponse, ConfigResponse,
+    GameDeletedResponse, GameHealth, GameStateResponse, GameSummary, GamesResponse, HealthResponse,
+    InitResponse, LeaderboardResponse, LobbyStatusResponse, PeekResponse, PingResponse,
+    PlayerResponse, RankingResponse, TurnResponse,
+};
+use crate::sse_utils::{broadcast_sse, send_event, send_sse, EventSender, GameEvent};
+use futures_util::{SinkExt, StreamExt};
 use tokio::sync::RwLockWriteGuard;
 use tokio_stream::wrappers::ReceiverStream;
+use tracing::{info, warn};
 use warp::reply::{WithHeader, WithStatus};
-use warp::{reply::Json, sse::Event, Rejection, Reply};
+use warp::ws::{WebSocket, Ws};
+use warp::{reply::Json, Rejection, Reply};
 
-use memory_backend::memory::{GameState, Memory, MemoryStore, Player, Store};
-use memory_backend::queries::{CreateQuery, JoinQuery, PickQuery};
-use memory_backend::reject::{
-    AlreadyExists, AlreadyRunning, InvalidMasterKey, InvalidToken, NoGameExists, NotYetRunning,
-    NotYourTurn,
+use crate::deck::{malformed_urls, unreachable_urls};
+use crate::memory::{
+    unix_timestamp, GameConfig, GameState, Memory, MemoryStore, Scoring, Store, MAX_REVEAL_MS,
+    MAX_REVEAL_MULTIPLIER,
+};
+use crate::queries::{
+    key_weakness, parse_layout, valid_id, valid_name, AnnounceQuery, ChatQuery, ClaimAdminBody,
+    CreateQuery, GameQuery, GamesQuery, JoinQuery, KickQuery, LeaderboardQuery, PickQuery,
+    RotateKeyBody, TransferAdminQuery, WsAction,
+};
+use crate::reject::{
+    error_response, AlreadyExists, AlreadyRunning, GameFull, GamePaused, InvalidBoardSize,
+    InvalidCard, InvalidGameAdmin, InvalidImages, InvalidInput, InvalidMasterKey, InvalidMessage,
+    InvalidToken, NoGameExists, NotEnoughPlayers, NotInLobby, NotYetRunning, NotYourTurn,
+    PlayerNotFound, ResolvingMatch, TooFast, TooManyGames, TooManyRequests, WeakKey,
 };
 
-pub async fn ping(query: Option<String>, store: Store) -> Result<impl Reply, Rejection> {
+const MAX_CHAT_LENGTH: usize = 280;
+const MAX_ANNOUNCEMENT_LENGTH: usize = 500;
+const PEEK_MS: u64 = 1000;
+const REAP_INTERVAL: Duration = Duration::from_secs(60);
+const DISCONNECT_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// How long before a lobby timeout `lobbyTimeout` warns about it.
+const LOBBY_WARNING: Duration = Duration::from_secs(10);
+
+pub async fn ping(
+    token: Option<String>,
+    query: GameQuery,
+    store: Store,
+) -> Result<impl Reply, Rejection> {
     let lock = store.read().await;
-    if lock.game.is_none() {
-        return Err(warp::reject::custom(NoGameExists));
-    }
+    let game = lock.get_game(&query.id)?;
 
-    let reply = warp::reply::json(&lock.game.as_ref().unwrap().id);
-    if let Some(token) = query {
-        if lock.game.as_ref().unwrap().players.get(&token).is_none() {
-            return remove_cookie_response("memory_token", reply);
+    let reply = warp::reply::json(&PingResponse {
+        id: game.id.clone(),
+        player_count: game.players.len(),
+        state: game.state,
+    });
+    if let Some(token) = token {
+        if !game.players.contains_key(&token) {
+            return remove_cookie_response("memory_token", reply, lock.dev_mode);
         }
     }
 
@@ -31,164 +68,1565 @@ pub async fn ping(query: Option<String>, store: Store) -> Result<impl Reply, Rej
     Ok(warp::reply::with_header(reply, "", ""))
 }
 
+pub async fn health(store: Store) -> Result<Json, Rejection> {
+    let lock = store.read().await;
+    let games = lock
+        .games
+        .values()
+        .map(|game| GameHealth {
+            id: game.id.clone(),
+            game_state: game.state,
+            connected_players: game.players.values().filter(|p| p.is_connected()).count(),
+        })
+        .collect();
+
+    Ok(warp::reply::json(&HealthResponse { games }))
+}
+
+pub async fn metrics() -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::with_header(
+        crate::metrics::render(),
+        "Content-Type",
+        "text/plain; version=0.0.4",
+    ))
+}
+
+pub async fn list_games(query: GamesQuery, store: Store) -> Result<Json, Rejection> {
+    let lobby_only = query.lobby.unwrap_or(false);
+    let lock = store.read().await;
+    let mut games: Vec<GameSummary> = lock
+        .games
+        .values()
+        .filter(|game| !lobby_only || matches!(game.state, GameState::Lobby))
+        .map(game_summary)
+        .collect();
+    games.sort_by(|a, b| a.id.cmp(&b.id));
+
+    Ok(warp::reply::json(&GamesResponse { games }))
+}
+
+fn game_summary(game: &Memory) -> GameSummary {
+    GameSummary {
+        id: game.id.clone(),
+        state: game.state,
+        player_count: game.players.len(),
+        max_players: game.max_players,
+    }
+}
+
+/// Streams service-wide events to an operator: games created, deleted and
+/// finished, and players joining, leaving and being kicked. The stream opens
+/// with every current game.
+pub async fn admin_stream(master_key: String, store: Store) -> Result<impl Reply, Rejection> {
+    let mut lock = store.write().await;
+    if master_key != lock.master_key {
+        return Err(warp::reject::custom(InvalidMasterKey));
+    }
+
+    let (sender, receiver) = tokio::sync::mpsc::channel::<GameEvent>(16);
+    lock.admin_senders.push(sender.clone());
+
+    let (closed_tx, closed_rx) = tokio::sync::oneshot::channel::<()>();
+    tokio::spawn(remove_admin_on_close(
+        closed_rx,
+        sender.downgrade(),
+        store.clone(),
+    ));
+
+    let receiver_stream = ReceiverStream::new(receiver).map(move |event| {
+        let _ = &closed_tx;
+        Ok::<_, Infallible>(event.into_sse())
+    });
+    let stream = warp::sse::keep_alive().stream(receiver_stream);
+
+    let mut games: Vec<GameSummary> = lock.games.values().map(game_summary).collect();
+    games.sort_by(|a, b| a.id.cmp(&b.id));
+    let _ = send_sse("init", &GamesResponse { games }, Some(&sender)).await;
+    info!("Admin stream opened");
+
+    Ok(warp::sse::reply(stream))
+}
+
+async fn remove_admin_on_close(
+    closed: tokio::sync::oneshot::Receiver<()>,
+    sender: tokio::sync::mpsc::WeakSender<GameEvent>,
+    store: Store,
+) {
+    let _ = closed.await;
+    if let Some(sender) = sender.upgrade() {
+        store.write().await.drop_admin(&sender);
+        info!("Admin stream closed");
+    }
+}
+
 pub async fn check_key(key: String, store: Store) -> Result<impl Reply, Rejection> {
     let lock = store.read().await;
     if lock.master_key == key {
-        set_cookie_reponse("master_key", key)
+        set_cookie_reponse("master_key", key, lock.dev_mode)
     } else {
         Err(warp::reject::custom(InvalidMasterKey))
     }
 }
 
-pub async fn create(
+/// Replaces the master key. The new key has to pass the same strength check
+/// as `MASTER_KEY` at startup, unless weak keys are allowed.
+pub async fn rotate_key(
     master_key: String,
-    query: CreateQuery,
+    body: RotateKeyBody,
+    store: Store,
+) -> Result<impl Reply, Rejection> {
+    let mut lock = store.write().await;
+
+    if master_key != lock.master_key || body.key.is_empty() {
+        return Err(warp::reject::custom(InvalidMasterKey));
+    }
+    if let Some(weakness) = key_weakness(&body.key, lock.min_key_length) {
+        if !lock.allow_weak_key {
+            warn!(weakness, "Refused a weak master key");
+            return Err(warp::reject::custom(WeakKey));
+        }
+        warn!(weakness, "Rotating to a weak master key");
+    }
+    lock.master_key = body.key.clone();
+    info!("Master key rotated");
+    set_cookie_reponse("master_key", body.key, lock.dev_mode)
+}
+
+/// Sends an operator message to every player and spectator of one game, or
+/// of all games when no id is given.
+pub async fn announce(
+    master_key: String,
+    query: AnnounceQuery,
     store: Store,
 ) -> Result<Json, Rejection> {
+    let message = query.message.trim();
+    if message.is_empty() || message.chars().count() > MAX_ANNOUNCEMENT_LENGTH {
+        return Err(warp::reject::custom(InvalidMessage));
+    }
+
     let mut lock = store.write().await;
+    if master_key != lock.master_key {
+        return Err(warp::reject::custom(InvalidMasterKey));
+    }
+
+    let res = AnnouncementResponse {
+        message: message.to_owned(),
+        timestamp: unix_timestamp(),
+    };
+    match &query.id {
+        Some(id) => {
+            let game = lock.get_game_mut(id)?;
+            game.broadcast("announcement", &res).await;
+        }
+        None => {
+            for game in lock.games.values_mut() {
+                game.broadcast("announcement", &res).await;
+            }
+        }
+    }
+    info!(game = ?query.id, "Announcement sent");
+    Ok(warp::reply::json(&"Announced"))
+}
+
+/// Lets a request through while its address has tokens left, see
+/// `RateLimit`. Requests without a known address are never limited.
+pub async fn rate_limit(addr: Option<SocketAddr>, store: Store) -> Result<(), Rejection> {
+    let Some(addr) = addr else {
+        return Ok(());
+    };
+    if store.write().await.take_token(addr.ip(), Instant::now()) {
+        Ok(())
+    } else {
+        warn!(ip = %addr.ip(), "Rate limit exceeded");
+        Err(warp::reject::custom(TooManyRequests))
+    }
+}
+
+pub async fn create(
+    master_key: String,
+    query: CreateQuery,
+    images: Option<Vec<String>>,
+    store: Store,
+) -> Result<impl Reply, Rejection> {
+    // Checked up front too, so nobody without the key can make us fetch URLs.
+    if master_key != store.read().await.master_key {
+        return Err(warp::reject::custom(InvalidMasterKey));
+    }
+    if let Some(images) = &images {
+        check_images(images, store.read().await.check_images).await?;
+    }
 
+    let mut lock = store.write().await;
     if master_key == lock.master_key {
-        create_new_game(&mut lock, query.id)
+        let id = query.id.clone();
+        let admin_token = create_new_game(&mut lock, query, images)?;
+        let res = game_summary(lock.get_game(&id)?);
+        lock.notify_admins("gameCreated", res).await;
+        set_cookie_reponse(&admin_cookie_name(&id), admin_token, lock.dev_mode)
     } else {
         Err(warp::reject::custom(InvalidMasterKey))
     }
 }
 
-pub async fn delete(master_key: String, store: Store) -> Result<Json, Rejection> {
+pub async fn delete(
+    admin_token: String,
+    query: GameQuery,
+    store: Store,
+) -> Result<Json, Rejection> {
     let mut lock = store.write().await;
+    if admin_token != lock.get_game(&query.id)?.admin_token {
+        return Err(warp::reject::custom(InvalidGameAdmin));
+    }
 
-    if master_key == lock.master_key {
-        lock.game = None;
-        print!("Game deleted.");
-        Ok(warp::reply::json(&"Game deleted"))
+    let Some(mut game) = lock.remove_game(&query.id) else {
+        return Err(warp::reject::custom(NoGameExists));
+    };
+    let res = GameDeletedResponse {
+        id: game.id.clone(),
+    };
+    game.broadcast("gameDeleted", res).await;
+    // Dropping the game drops every sender, which ends the streams.
+    drop(game);
+    let res = GameDeletedResponse {
+        id: query.id.clone(),
+    };
+    lock.notify_admins("gameDeleted", res).await;
+    lock.save();
+    info!(game = %query.id, "Game deleted");
+    Ok(warp::reply::json(&"Game deleted"))
+}
+
+pub async fn restart(
+    admin_token: String,
+    query: GameQuery,
+    store: Store,
+) -> Result<Json, Rejection> {
+    let mut lock = store.write().await;
+    let lobby_timeout = lock.lobby_timeout;
+    let game = lock.get_game_mut(&query.id)?;
+    game.touch();
+    if admin_token != game.admin_token {
+        return Err(warp::reject::custom(InvalidGameAdmin));
+    }
+
+    if let GameState::Starting | GameState::Running | GameState::Paused = game.state {
+        return Err(warp::reject::custom(AlreadyRunning));
+    }
+
+    game.reset();
+    game.broadcast("state", game.get_state(None)).await;
+    // The players stay seated, so no join arms the timer for the rematch.
+    if !game.players.is_empty() && !lobby_timeout.is_zero() {
+        let armed = game.arm_lobby_timer();
+        schedule_lobby_timeout(store.clone(), query.id.clone(), armed, lobby_timeout);
+    }
+    lock.save();
+    Ok(warp::reply::json(&"Restarted"))
+}
+
+pub async fn pause(admin_token: String, query: GameQuery, store: Store) -> Result<Json, Rejection> {
+    let mut lock = store.write().await;
+    let game = lock.get_game_mut(&query.id)?;
+    game.touch();
+    if admin_token != game.admin_token {
+        return Err(warp::reject::custom(InvalidGameAdmin));
+    }
+
+    match game.state {
+        GameState::Running => (),
+        GameState::Paused => return Err(warp::reject::custom(GamePaused)),
+        _ => return Err(warp::reject::custom(NotYetRunning)),
+    }
+
+    game.pause();
+    let res = GameStateResponse {
+        game_state: game.state,
+    };
+    game.broadcast("paused", res).await;
+    lock.save();
+    Ok(warp::reply::json(&"Paused"))
+}
+
+pub async fn resume(
+    admin_token: String,
+    query: GameQuery,
+    store: Store,
+) -> Result<Json, Rejection> {
+    let mut lock = store.write().await;
+    let timeout = lock.turn_timeout;
+    let game = lock.get_game_mut(&query.id)?;
+    game.touch();
+    if admin_token != game.admin_token {
+        return Err(warp::reject::custom(InvalidGameAdmin));
+    }
+
+    match game.state {
+        GameState::Paused => (),
+        GameState::Running => return Err(warp::reject::custom(AlreadyRunning)),
+        _ => return Err(warp::reject::custom(NotYetRunning)),
+    }
+
+    game.flush_unflips().await;
+    game.resume();
+    let res = GameStateResponse {
+        game_state: game.state,
+    };
+    game.broadcast("resumed", res).await;
+    let turn = game.turn_number();
+    if game.has_pending_mismatch() {
+        let delay = game.reveal_delay();
+        schedule_mismatch_hide(store.clone(), query.id, turn, delay, timeout);
     } else {
-        Err(warp::reject::custom(InvalidMasterKey))
+        schedule_turn_timeout(store.clone(), query.id, turn, timeout);
+    }
+    lock.save();
+    Ok(warp::reply::json(&"Resumed"))
+}
+
+pub async fn board(admin_token: String, query: GameQuery, store: Store) -> Result<Json, Rejection> {
+    let lock = store.read().await;
+    let game = lock.get_game(&query.id)?;
+    if admin_token != game.admin_token {
+        return Err(warp::reject::custom(InvalidGameAdmin));
+    }
+
+    Ok(warp::reply::json(&BoardResponse {
+        columns: game.columns,
+        rows: game.rows,
+        layout: &game.layout,
+        cards: game.cards.iter().map(CardResponse::from).collect(),
+    }))
+}
+
+/// One card face up, a cheaper check than the board for a disputed flip.
+pub async fn card(
+    card_id: usize,
+    admin_token: String,
+    query: GameQuery,
+    store: Store,
+) -> Result<Json, Rejection> {
+    let lock = store.read().await;
+    let game = lock.get_game(&query.id)?;
+    if admin_token != game.admin_token {
+        return Err(warp::reject::custom(InvalidGameAdmin));
     }
+
+    let card = game
+        .cards
+        .get(card_id)
+        .ok_or_else(|| warp::reject::custom(InvalidCard))?;
+    Ok(warp::reply::json(&CardResponse::from(card)))
+}
+
+pub async fn history(
+    admin_token: String,
+    query: GameQuery,
+    store: Store,
+) -> Result<Json, Rejection> {
+    let lock = store.read().await;
+    let game = lock.get_game(&query.id)?;
+    if admin_token != game.admin_token {
+        return Err(warp::reject::custom(InvalidGameAdmin));
+    }
+
+    Ok(warp::reply::json(&game.history))
 }
 
 pub async fn join(query: JoinQuery, store: Store) -> Result<impl Reply, Rejection> {
+    let reveal_multiplier = query.reveal_multiplier.unwrap_or(1.0);
+    if !valid_name(&query.name) || !(1.0..=MAX_REVEAL_MULTIPLIER).contains(&reveal_multiplier) {
+        return Err(warp::reject::custom(InvalidInput));
+    }
+
     let mut lock = store.write().await;
-    let game = lock.game.as_mut().unwrap();
+    let dev_mode = lock.dev_mode;
+    let lobby_timeout = lock.lobby_timeout;
+    let game = lock.get_game_mut(&query.id)?;
+    game.touch();
 
     match game.state {
+        GameState::Lobby if game.players.len() >= game.max_players => {
+            return Err(warp::reject::custom(GameFull));
+        }
         GameState::Lobby => (),
-        _ => return Err(warp::reject::custom(AlreadyRunning)),
+        _ => {
+            return match game.reconnect_player(&query.name) {
+                Some(token) => {
+                    lock.save();
+                    set_cookie_reponse("memory_token", token, dev_mode)
+                }
+                None => Err(warp::reject::custom(AlreadyRunning)),
+            };
+        }
     }
-    if let Ok(token) = game.add_new_player(query.name) {
-        update_leaderboard(game.players.values().collect()).await;
-        set_cookie_reponse("memory_token", token)
-    } else {
-        Err(warp::reject::custom(AlreadyExists))
+    let token = game
+        .add_new_player(query.name.clone(), query.color.as_deref())
+        .map_err(warp::reject::custom)?;
+    game.players.get_mut(&token).unwrap().reveal_multiplier = reveal_multiplier;
+    let res = PlayerResponse {
+        name: query.name.clone(),
+        color: game.players[&token].color.clone(),
+    };
+    game.broadcast("playerJoined", res).await;
+    update_leaderboard(game).await;
+    update_lobby_status(game).await;
+    if game.players.len() == 1 && !lobby_timeout.is_zero() {
+        let armed = game.arm_lobby_timer();
+        schedule_lobby_timeout(store.clone(), query.id.clone(), armed, lobby_timeout);
+    }
+    let res = AdminPlayerResponse {
+        id: query.id,
+        name: query.name,
+    };
+    lock.notify_admins("playerJoined", res).await;
+    lock.save();
+    set_cookie_reponse("memory_token", token, dev_mode)
+}
+
+pub async fn leave(token: String, query: GameQuery, store: Store) -> Result<impl Reply, Rejection> {
+    let mut lock = store.write().await;
+    let timeout = lock.turn_timeout;
+    let countdown = lock.countdown;
+    let game = lock.get_game_mut(&query.id)?;
+    game.touch();
+
+    let Some(name) = remove_player(game, &token, &store, countdown, timeout).await else {
+        return Err(warp::reject::custom(InvalidToken));
+    };
+
+    let res = AdminPlayerResponse { id: query.id, name };
+    lock.notify_admins("playerLeft", res).await;
+    lock.save();
+    Ok(warp::reply::with_header(
+        warp::reply::json(&"Left game"),
+        "Set-Cookie",
+        removed_cookie_header("memory_token", lock.dev_mode),
+    ))
+}
+
+pub async fn kick(admin_token: String, query: KickQuery, store: Store) -> Result<Json, Rejection> {
+    let mut lock = store.write().await;
+    let timeout = lock.turn_timeout;
+    let countdown = lock.countdown;
+    let game = lock.get_game_mut(&query.id)?;
+    game.touch();
+    if admin_token != game.admin_token {
+        return Err(warp::reject::custom(InvalidGameAdmin));
     }
+
+    let token = game
+        .players
+        .iter()
+        .find(|(_, p)| p.name == query.name)
+        .map(|(token, _)| token.clone())
+        .ok_or_else(|| warp::reject::custom(PlayerNotFound))?;
+    remove_player(game, &token, &store, countdown, timeout).await;
+
+    info!(game = %query.id, player = %query.name, "Player kicked");
+    let res = AdminPlayerResponse {
+        id: query.id,
+        name: query.name,
+    };
+    lock.notify_admins("playerKicked", res).await;
+    lock.save();
+    Ok(warp::reply::json(&"Kicked"))
+}
+
+/// Makes a connected player the game admin. Their streams get the new token
+/// as `adminToken` to exchange at `claim_admin`; the old token stops working
+/// and everyone learns about the change from `adminChanged`. Players without
+/// a stream could not receive the token and count as not found.
+pub async fn transfer_admin(
+    admin_token: String,
+    query: TransferAdminQuery,
+    store: Store,
+) -> Result<Json, Rejection> {
+    let mut lock = store.write().await;
+    let game = lock.get_game_mut(&query.id)?;
+    game.touch();
+    if admin_token != game.admin_token {
+        return Err(warp::reject::custom(InvalidGameAdmin));
+    }
+
+    let token = game
+        .players
+        .iter()
+        .find(|(_, p)| p.name == query.name && p.is_connected())
+        .map(|(token, _)| token.clone())
+        .ok_or_else(|| warp::reject::custom(PlayerNotFound))?;
+    let res = AdminTokenResponse {
+        admin_token: game.rotate_admin_token(),
+    };
+    for sender in &game.players[&token].senders {
+        let _ = send_sse("adminToken", &res, Some(sender)).await;
+    }
+    let res = AdminChangedResponse {
+        name: query.name.clone(),
+    };
+    game.broadcast("adminChanged", res).await;
+
+    info!(game = %query.id, player = %query.name, "Game admin transferred");
+    lock.save();
+    Ok(warp::reply::json(&"Transferred"))
 }
 
-pub async fn game_message(token: String, store: Store) -> Result<impl Reply, Rejection> {
-    let (sender, receiver) = tokio::sync::mpsc::channel::<Result<Event, Infallible>>(2);
+/// Sets the admin cookie of a game from a token its streams handed out as
+/// `adminToken`, since browsers can't set the HttpOnly cookie themselves.
+pub async fn claim_admin(
+    query: GameQuery,
+    body: ClaimAdminBody,
+    store: Store,
+) -> Result<impl Reply, Rejection> {
+    let mut lock = store.write().await;
+    let game = lock.get_game_mut(&query.id)?;
+    game.touch();
+    if body.admin_token != game.admin_token {
+        return Err(warp::reject::custom(InvalidGameAdmin));
+    }
+    info!(game = %query.id, "Game admin claimed");
+    set_cookie_reponse(
+        &admin_cookie_name(&query.id),
+        body.admin_token,
+        lock.dev_mode,
+    )
+}
 
+/// Streams the game's events to a player. A client resuming with
+/// `Last-Event-ID` first gets the broadcasts it missed, or a fresh `init`
+/// if they have left the event log.
+pub async fn game_message(
+    token: String,
+    query: GameQuery,
+    last_event_id: Option<u64>,
+    store: Store,
+) -> Result<impl Reply, Rejection> {
     let mut lock = store.write().await;
-    let game = lock.game.as_mut().unwrap();
+    let game = lock.get_game_mut(&query.id)?;
+    game.touch();
+    if !game.players.contains_key(&token) {
+        return Err(warp::reject::custom(InvalidToken));
+    }
 
-    let player = game.players.get_mut(&token).unwrap();
-    let ready = player.ready.clone();
-    player.sender = Some(sender.clone());
+    let missed = last_event_id.and_then(|id| game.events_since(id));
+    // Room for everything sent below, so none of it waits on the client.
+    let capacity = missed.as_ref().map_or(0, Vec::len) + 2;
+    let (sender, receiver) = tokio::sync::mpsc::channel::<GameEvent>(capacity);
 
-    let receiver_stream = ReceiverStream::new(receiver);
+    let player = game
+        .players
+        .get_mut(&token)
+        .ok_or_else(|| warp::reject::custom(InvalidToken))?;
+    if player.add_sender(sender.clone()) {
+        info!(game = %query.id, player = %player.name, "Player reconnected");
+    }
+
+    // The stream owns `closed_tx`, so `closed_rx` resolves once warp drops
+    // the stream after the client goes away.
+    let (closed_tx, closed_rx) = tokio::sync::oneshot::channel::<()>();
+    tokio::spawn(clear_sender_on_close(
+        closed_rx,
+        sender.downgrade(),
+        query.id.clone(),
+        token.clone(),
+        store.clone(),
+    ));
+
+    let receiver_stream = ReceiverStream::new(receiver).map(move |event| {
+        let _ = &closed_tx;
+        Ok::<_, Infallible>(event.into_sse())
+    });
     let stream = warp::sse::keep_alive().stream(receiver_stream);
 
-    send_state(&game.get_state(ready), &sender).await;
+    match missed {
+        Some(events) => {
+            info!(game = %query.id, count = events.len(), "Replaying missed events");
+            for event in events {
+                let _ = send_event(event, &sender).await;
+            }
+        }
+        None => send_init(&game.get_state(Some(&token)), &sender).await,
+    }
 
     Ok(warp::sse::reply(stream))
 }
 
-pub async fn send_state(
-    res: &InitResponse,
-    sender: &tokio::sync::mpsc::Sender<Result<Event, Infallible>>,
+pub async fn state(token: String, query: GameQuery, store: Store) -> Result<Json, Rejection> {
+    let lock = store.read().await;
+    let game = lock.get_game(&query.id)?;
+    if !game.players.contains_key(&token) {
+        return Err(warp::reject::custom(InvalidToken));
+    }
+
+    Ok(warp::reply::json(&game.get_state(Some(&token))))
+}
+
+pub async fn config(query: GameQuery, store: Store) -> Result<Json, Rejection> {
+    let lock = store.read().await;
+    let game = lock.get_game(&query.id)?;
+
+    Ok(warp::reply::json(&ConfigResponse {
+        columns: game.columns,
+        rows: game.rows,
+        layout: game.layout.clone(),
+        size: game.size,
+        match_size: game.match_size,
+        max_players: game.max_players,
+        turn_timeout_secs: lock.turn_timeout.as_secs(),
+        reveal_ms: game.reveal_ms,
+        countdown_secs: lock.countdown.as_secs(),
+        streak_bonus: game.streak_bonus,
+        mismatch_penalty: game.mismatch_penalty,
+        practice: game.practice,
+        target_matches: game.target_matches,
+        peeks: game.peeks,
+        turn_mode: game.turn_mode,
+        scoring: game.scoring,
+    }))
+}
+
+pub async fn turn(query: GameQuery, store: Store) -> Result<Json, Rejection> {
+    let lock = store.read().await;
+    let game = lock.get_game(&query.id)?;
+    let name = game
+        .current_player()
+        .ok_or_else(|| warp::reject::custom(NotYetRunning))?;
+
+    Ok(warp::reply::json(&TurnResponse {
+        name: name.to_owned(),
+    }))
+}
+
+pub async fn leaderboard(query: LeaderboardQuery, store: Store) -> Result<Json, Rejection> {
+    let lock = store.read().await;
+    let game = lock.get_game(&query.id)?;
+
+    Ok(warp::reply::json(&RankingResponse::from(
+        game.standings(),
+        query.top,
+    )))
+}
+
+pub async fn watch(query: GameQuery, store: Store) -> Result<impl Reply, Rejection> {
+    let (sender, receiver) = tokio::sync::mpsc::channel::<GameEvent>(2);
+
+    let mut lock = store.write().await;
+    let dev_mode = lock.dev_mode;
+    let game = lock.get_game_mut(&query.id)?;
+    game.touch();
+    let spectator_token = game.add_spectator(sender.clone());
+
+    let (closed_tx, closed_rx) = tokio::sync::oneshot::channel::<()>();
+    tokio::spawn(remove_spectator_on_close(
+        closed_rx,
+        sender.downgrade(),
+        query.id.clone(),
+        store.clone(),
+    ));
+
+    let receiver_stream = ReceiverStream::new(receiver).map(move |event| {
+        let _ = &closed_tx;
+        Ok::<_, Infallible>(event.into_sse())
+    });
+    let stream = warp::sse::keep_alive().stream(receiver_stream);
+
+    send_init(&game.get_state(None), &sender).await;
+
+    Ok(warp::reply::with_header(
+        warp::sse::reply(stream),
+        "Set-Cookie",
+        cookie_header("spectator_token", &spectator_token, dev_mode),
+    ))
+}
+
+pub async fn promote(
+    spectator_token: String,
+    query: JoinQuery,
+    store: Store,
+) -> Result<impl Reply, Rejection> {
+    let reveal_multiplier = query.reveal_multiplier.unwrap_or(1.0);
+    if !valid_name(&query.name) || !(1.0..=MAX_REVEAL_MULTIPLIER).contains(&reveal_multiplier) {
+        return Err(warp::reject::custom(InvalidInput));
+    }
+
+    let mut lock = store.write().await;
+    let dev_mode = lock.dev_mode;
+    let game = lock.get_game_mut(&query.id)?;
+    game.touch();
+
+    match game.state {
+        GameState::Lobby if game.players.len() >= game.max_players => {
+            return Err(warp::reject::custom(GameFull));
+        }
+        GameState::Lobby => (),
+        _ => return Err(warp::reject::custom(NotInLobby)),
+    }
+    if !game.has_spectator(&spectator_token) {
+        return Err(warp::reject::custom(InvalidToken));
+    }
+
+    let token = game
+        .add_new_player(query.name.clone(), query.color.as_deref())
+        .map_err(warp::reject::custom)?;
+    game.players.get_mut(&token).unwrap().reveal_multiplier = reveal_multiplier;
+    game.promote_spectator(&spectator_token, &token);
+    info!(game = %query.id, player = %query.name, "Spectator joined as player");
+
+    let res = PlayerResponse {
+        name: query.name.clone(),
+        color: game.players[&token].color.clone(),
+    };
+    game.broadcast("playerJoined", res).await;
+    update_leaderboard(game).await;
+    update_lobby_status(game).await;
+    let res = AdminPlayerResponse {
+        id: query.id,
+        name: query.name,
+    };
+    lock.notify_admins("playerJoined", res).await;
+    lock.save();
+    set_cookie_reponse("memory_token", token, dev_mode)
+}
+
+async fn remove_spectator_on_close(
+    closed: tokio::sync::oneshot::Receiver<()>,
+    sender: tokio::sync::mpsc::WeakSender<GameEvent>,
+    id: String,
+    store: Store,
+) {
+    let _ = closed.await;
+    let Some(sender) = sender.upgrade() else {
+        return;
+    };
+
+    // The stream may belong to a player by now, if the spectator joined.
+    let mut lock = store.write().await;
+    if let Ok(game) = lock.get_game_mut(&id) {
+        game.drop_channel(&sender);
+    }
+}
+
+async fn clear_sender_on_close(
+    closed: tokio::sync::oneshot::Receiver<()>,
+    sender: tokio::sync::mpsc::WeakSender<GameEvent>,
+    id: String,
+    token: String,
+    store: Store,
 ) {
-    send_sse("state", res, Some(sender)).await;
+    let _ = closed.await;
+    if let Some(sender) = sender.upgrade() {
+        clear_sender(&store, &id, &token, &sender).await;
+    }
+}
+
+async fn clear_sender(store: &Store, id: &str, token: &str, sender: &EventSender) {
+    let mut lock = store.write().await;
+    let Ok(game) = lock.get_game_mut(id) else {
+        return;
+    };
+    if let Some(player) = game.players.get_mut(token) {
+        if player.remove_sender(sender) {
+            info!(game = %id, player = %player.name, "Player disconnected");
+        }
+    }
+}
+
+pub async fn ws(
+    token: String,
+    query: GameQuery,
+    socket: Ws,
+    store: Store,
+) -> Result<impl Reply, Rejection> {
+    let lock = store.read().await;
+    let game = lock.get_game(&query.id)?;
+    if !game.players.contains_key(&token) {
+        return Err(warp::reject::custom(InvalidToken));
+    }
+
+    let store = store.clone();
+    Ok(socket.on_upgrade(move |socket| ws_session(socket, token, query.id, store)))
+}
+
+async fn ws_session(socket: WebSocket, token: String, id: String, store: Store) {
+    let (mut ws_sender, mut ws_receiver) = socket.split();
+    let (sender, mut receiver) = tokio::sync::mpsc::channel::<GameEvent>(16);
+
+    {
+        let mut lock = store.write().await;
+        let Ok(game) = lock.get_game_mut(&id) else {
+            return;
+        };
+        game.touch();
+        let Some(player) = game.players.get_mut(&token) else {
+            return;
+        };
+        if player.add_sender(sender.clone()) {
+            info!(game = %id, player = %player.name, "Player reconnected");
+        }
+        send_init(&game.get_state(Some(&token)), &sender).await;
+    }
+
+    // Only the store holds on to the channel, so a kick, delete or reap that
+    // drops it ends the forwarding and with it the socket.
+    let weak_sender = sender.downgrade();
+    drop(sender);
+    let mut forward = tokio::spawn(async move {
+        while let Some(event) = receiver.recv().await {
+            if ws_sender.send(event.into_ws()).await.is_err() {
+                return;
+            }
+        }
+        let _ = ws_sender.close().await;
+    });
+
+    loop {
+        let message = tokio::select! {
+            message = ws_receiver.next() => message,
+            _ = &mut forward => break,
+        };
+        let Some(Ok(message)) = message else {
+            break;
+        };
+        let Ok(text) = message.to_str() else {
+            continue;
+        };
+        let result = match serde_json::from_str::<WsAction>(text) {
+            Ok(WsAction::Pick { card }) => {
+                let query = PickQuery {
+                    id: id.clone(),
+                    card,
+                };
+                pick_card(token.clone(), query, store.clone())
+                    .await
+                    .map(|_| ())
+            }
+            Ok(WsAction::Ready) => {
+                let query = GameQuery { id: id.clone() };
+                ready(token.clone(), query, store.clone()).await.map(|_| ())
+            }
+            Err(_) => Err(warp::reject::custom(InvalidMessage)),
+        };
+        if let (Err(rejection), Some(sender)) = (result, weak_sender.upgrade()) {
+            let _ = send_sse("error", &error_response(&rejection), Some(&sender)).await;
+        }
+    }
+
+    if let Some(sender) = weak_sender.upgrade() {
+        clear_sender(&store, &id, &token, &sender).await;
+    }
+}
+
+/// Sends the snapshot a fresh stream starts with. Callers hold the write lock
+/// that registered `sender`, so no event can slip in before it.
+pub async fn send_init(res: &InitResponse, sender: &EventSender) {
+    let _ = send_sse("init", res, Some(sender)).await;
 }
 
 pub async fn pick_card(token: String, query: PickQuery, store: Store) -> Result<Json, Rejection> {
     let mut lock = store.write().await;
-    let game = lock.game.as_mut().unwrap();
+    let timeout = lock.turn_timeout;
+    let pick_interval = lock.pick_interval;
+    let game = lock.get_game_mut(&query.id)?;
+    game.touch();
 
     match game.state {
         GameState::Running => (),
+        GameState::Paused => return Err(warp::reject::custom(GamePaused)),
         _ => return Err(warp::reject::custom(NotYetRunning)),
     }
+    if game.has_pending_mismatch() {
+        return Err(warp::reject::custom(ResolvingMatch));
+    }
 
-    if let Some(player) = game.players.get(&token) {
+    if let Some(player) = game.players.get_mut(&token) {
         if !player.turn {
             return Err(warp::reject::custom(NotYourTurn));
         }
+        let now = Instant::now();
+        if player
+            .last_pick
+            .is_some_and(|last| now.duration_since(last) < pick_interval)
+        {
+            return Err(warp::reject::custom(TooFast));
+        }
+        player.last_pick = Some(now);
     } else {
         return Err(warp::reject::custom(InvalidToken));
     }
 
+    let turn = game.turn_number();
     let reply = game.pick_card(query.card, token).await;
-    update_leaderboard(game.players.values().collect()).await;
+    let game_over = matches!(game.state, GameState::Finished).then(|| AdminGameOverResponse {
+        id: game.id.clone(),
+        winners: game.winners.clone(),
+        leaderboard: game.standings(),
+        duration_secs: game.duration_secs,
+    });
+    let res = LeaderboardResponse {
+        last_scorer: game.last_scorer.clone(),
+        ..LeaderboardResponse::from(&game.players.values().collect())
+    };
+    game.broadcast("leaderboard", res).await;
+    if game.has_pending_mismatch() {
+        let delay = game.reveal_delay();
+        schedule_mismatch_hide(store.clone(), query.id, turn, delay, timeout);
+    } else if game.turn_number() != turn {
+        schedule_turn_timeout(store.clone(), query.id, game.turn_number(), timeout);
+    }
+    if let Some(res) = game_over {
+        lock.notify_admins("gameOver", res).await;
+    }
+    lock.save();
     reply
 }
 
-pub async fn ready(token: String, store: Store) -> Result<Json, Rejection> {
+/// Lets the active player take back a misclicked card before completing
+/// the set.
+pub async fn unflip(token: String, query: GameQuery, store: Store) -> Result<Json, Rejection> {
     let mut lock = store.write().await;
-    let game = lock.game.as_mut().unwrap();
+    let game = lock.get_game_mut(&query.id)?;
+    game.touch();
 
-    if let Some(player) = game.players.get_mut(&token) {
-        player.ready = true;
-        println!("{} is ready", player.name);
+    match game.state {
+        GameState::Running => (),
+        GameState::Paused => return Err(warp::reject::custom(GamePaused)),
+        _ => return Err(warp::reject::custom(NotYetRunning)),
+    }
+
+    let player = game
+        .players
+        .get(&token)
+        .ok_or_else(|| warp::reject::custom(InvalidToken))?;
+    if !player.turn {
+        return Err(warp::reject::custom(NotYourTurn));
+    }
+
+    game.unflip_last().await?;
+    lock.save();
+    Ok(warp::reply::json(&"Unflipped"))
+}
+
+/// Shows every card to the requesting player only, over their own stream.
+pub async fn peek(token: String, query: GameQuery, store: Store) -> Result<Json, Rejection> {
+    let mut lock = store.write().await;
+    let game = lock.get_game_mut(&query.id)?;
+    game.touch();
+
+    match game.state {
+        GameState::Running => (),
+        GameState::Paused => return Err(warp::reject::custom(GamePaused)),
+        _ => return Err(warp::reject::custom(NotYetRunning)),
+    }
+
+    let res = PeekResponse {
+        faces: game.peek(&token)?,
+        reveal_ms: PEEK_MS,
+    };
+    let player = &game.players[&token];
+    let peeks_left = player.peeks_left;
+    broadcast_sse("peekAll", &res, player.senders.iter().collect()).await;
+    lock.save();
+    Ok(warp::reply::json(&peeks_left))
+}
+
+pub async fn skip(token: String, query: GameQuery, store: Store) -> Result<Json, Rejection> {
+    let mut lock = store.write().await;
+    let timeout = lock.turn_timeout;
+    let game = lock.get_game_mut(&query.id)?;
+    game.touch();
+
+    match game.state {
+        GameState::Running => (),
+        GameState::Paused => return Err(warp::reject::custom(GamePaused)),
+        _ => return Err(warp::reject::custom(NotYetRunning)),
+    }
+
+    let player = game
+        .players
+        .get(&token)
+        .ok_or_else(|| warp::reject::custom(InvalidToken))?;
+    if !player.turn {
+        return Err(warp::reject::custom(NotYourTurn));
+    }
+    info!(game = %query.id, player = %player.name, "Turn skipped");
+
+    let delays = game.skip_turn().await;
+    schedule_unflips(store.clone(), query.id.clone(), game.turn_number(), delays);
+    update_leaderboard(game).await;
+    schedule_turn_timeout(store.clone(), query.id, game.turn_number(), timeout);
+    lock.save();
+    Ok(warp::reply::json(&"Skipped"))
+}
+
+pub async fn chat(token: String, query: ChatQuery, store: Store) -> Result<Json, Rejection> {
+    let message = query.message.trim();
+    if message.is_empty() || message.chars().count() > MAX_CHAT_LENGTH {
+        return Err(warp::reject::custom(InvalidMessage));
+    }
+
+    let mut lock = store.write().await;
+    let game = lock.get_game_mut(&query.id)?;
+    game.touch();
+    let player = game
+        .players
+        .get(&token)
+        .ok_or_else(|| warp::reject::custom(InvalidToken))?;
+
+    let res = ChatResponse {
+        name: player.name.clone(),
+        message: message.to_owned(),
+        timestamp: unix_timestamp(),
+    };
+    game.broadcast("chat", res).await;
+    Ok(warp::reply::json(&"Sent"))
+}
+
+/// Answers `Started`, or `Starting` while a countdown runs, only to the call
+/// that made every player ready; all others get `Success`.
+pub async fn ready(token: String, query: GameQuery, store: Store) -> Result<Json, Rejection> {
+    let mut lock = store.write().await;
+    let timeout = lock.turn_timeout;
+    let countdown = lock.countdown;
+    let game = lock.get_game_mut(&query.id)?;
+    game.touch();
+
+    if !game.mark_ready(&token)? {
+        update_leaderboard(game).await;
+        update_lobby_status(game).await;
+        lock.save();
+        return Ok(warp::reply::json(&"Success"));
+    }
+    if !game.has_enough_players() {
+        update_leaderboard(game).await;
+        update_lobby_status(game).await;
+        lock.save();
+        return Err(warp::reject::custom(NotEnoughPlayers));
+    }
+
+    let started = start_game(game, &store, countdown, timeout).await;
+    update_leaderboard(game).await;
+    update_lobby_status(game).await;
+    lock.save();
+    if started {
+        Ok(warp::reply::json(&"Started"))
     } else {
-        return Err(warp::reject::custom(InvalidToken));
+        Ok(warp::reply::json(&"Starting"))
     }
+}
 
-    for (_, player) in game.players.iter() {
-        if !player.ready {
-            update_leaderboard(game.players.values().collect()).await;
-            return Ok(warp::reply::json(&"Success"));
-        }
+pub async fn unready(token: String, query: GameQuery, store: Store) -> Result<Json, Rejection> {
+    let mut lock = store.write().await;
+    let game = lock.get_game_mut(&query.id)?;
+    game.touch();
+
+    match game.state {
+        GameState::Lobby => (),
+        _ => return Err(warp::reject::custom(AlreadyRunning)),
     }
 
-    game.start().await;
-    update_leaderboard(game.players.values().collect()).await;
-    Ok(warp::reply::json(&"Started"))
+    if let Some(player) = game.players.get_mut(&token) {
+        player.ready = false;
+        info!(game = %query.id, player = %player.name, "Player is no longer ready");
+    } else {
+        return Err(warp::reject::custom(InvalidToken));
+    }
+
+    update_leaderboard(game).await;
+    update_lobby_status(game).await;
+    lock.save();
+    Ok(warp::reply::json(&"Success"))
 }
 
-fn set_cookie_reponse(key: &str, value: String) -> Result<WithHeader<impl Reply>, Rejection> {
+fn set_cookie_reponse(
+    key: &str,
+    value: String,
+    dev_mode: bool,
+) -> Result<WithHeader<impl Reply>, Rejection> {
     Ok(warp::reply::with_header(
         warp::reply(),
         "Set-Cookie",
-        format!(
-            "{}={}; Path=/; Max-Age=31536000; SameSite=None; Secure; HttpOnly",
-            key, value,
-        ),
+        cookie_header(key, &value, dev_mode),
     ))
 }
 
+/// Browsers drop `Secure` cookies sent over plain HTTP, so development mode
+/// leaves it out and keeps the cookies first-party instead.
+fn cookie_attributes(dev_mode: bool) -> &'static str {
+    if dev_mode {
+        "SameSite=Lax; HttpOnly"
+    } else {
+        "SameSite=None; Secure; HttpOnly"
+    }
+}
+
+fn cookie_header(key: &str, value: &str, dev_mode: bool) -> String {
+    format!(
+        "{}={}; Path=/; Max-Age=31536000; {}",
+        key,
+        value,
+        cookie_attributes(dev_mode),
+    )
+}
+
+/// Every game keeps its admin token in a cookie of its own, so creating
+/// another game from the same browser keeps the first one manageable.
+pub fn admin_cookie_name(id: &str) -> String {
+    format!("admin_token_{}", id)
+}
+
+fn removed_cookie_header(key: &str, dev_mode: bool) -> String {
+    format!("{}=0; Max-Age=0; {}", key, cookie_attributes(dev_mode))
+}
+
 fn remove_cookie_response(
     key: &str,
     reply: Json,
+    dev_mode: bool,
 ) -> Result<WithHeader<WithStatus<Json>>, Rejection> {
-    println!("Removed token: {}", key);
+    info!(cookie = key, "Removed stale cookie");
     let reply = warp::reply::with_status(reply, warp::http::StatusCode::GONE);
-    return Ok(warp::reply::with_header(
+    Ok(warp::reply::with_header(
         reply,
         "Set-Cookie",
-        format!("{}=0; Max-Age=0; SameSite=None; Secure; HttpOnly", key),
-    ));
+        removed_cookie_header(key, dev_mode),
+    ))
+}
+
+/// Removes a player and keeps the game moving: a lobby whose remaining
+/// players are all ready starts, and a new turn gets its own timer. Returns
+/// the removed player's name.
+async fn remove_player(
+    game: &mut Memory,
+    token: &str,
+    store: &Store,
+    countdown: Duration,
+    timeout: Duration,
+) -> Option<String> {
+    let turn = game.turn_number();
+    let player = game.remove_player(token).await?;
+    let res = PlayerResponse {
+        name: player.name.clone(),
+        color: player.color,
+    };
+    game.broadcast("playerLeft", res).await;
+
+    if let GameState::Lobby = game.state {
+        if game.has_enough_players() && game.players.values().all(|p| p.ready) {
+            start_game(game, store, countdown, timeout).await;
+            return Some(player.name);
+        }
+    }
+    if game.turn_number() != turn {
+        schedule_turn_timeout(store.clone(), game.id.clone(), game.turn_number(), timeout);
+    }
+
+    update_leaderboard(game).await;
+    update_lobby_status(game).await;
+    Some(player.name)
+}
+
+/// Starts a game whose players are all ready. With a countdown configured
+/// the game only starts once it has run out, and `false` is returned.
+async fn start_game(
+    game: &mut Memory,
+    store: &Store,
+    countdown: Duration,
+    timeout: Duration,
+) -> bool {
+    if countdown.is_zero() {
+        game.start().await;
+        schedule_turn_timeout(store.clone(), game.id.clone(), game.turn_number(), timeout);
+        return true;
+    }
+
+    let turn = game.begin_countdown();
+    schedule_countdown(store.clone(), game.id.clone(), turn, countdown, timeout);
+    false
+}
+
+/// Broadcasts the seconds left once per second without holding the lock in
+/// between, then starts the game. Players leaving until fewer than needed
+/// remain abort the countdown and reopen the lobby.
+fn schedule_countdown(
+    store: Store,
+    id: String,
+    turn: usize,
+    countdown: Duration,
+    timeout: Duration,
+) {
+    tokio::spawn(async move {
+        let mut remaining = countdown.as_secs().max(1);
+        loop {
+            let mut lock = store.write().await;
+            let Ok(game) = lock.get_game_mut(&id) else {
+                return;
+            };
+            match game.state {
+                GameState::Starting if game.turn_number() == turn => (),
+                _ => return,
+            }
+            if !game.has_enough_players() {
+                game.abort_countdown();
+                game.broadcast("countdownAborted", "Not enough players")
+                    .await;
+                update_lobby_status(game).await;
+                lock.save();
+                return;
+            }
+            if remaining == 0 {
+                game.start().await;
+                update_leaderboard(game).await;
+                let turn = game.turn_number();
+                lock.save();
+                drop(lock);
+
+                schedule_turn_timeout(store, id, turn, timeout);
+                return;
+            }
+
+            game.broadcast("countdown", remaining).await;
+            drop(lock);
+            remaining -= 1;
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    });
+}
+
+/// Rejects malformed URLs and, if `reachable` is set, URLs that do not
+/// serve an image. Runs without the store lock, as the requests take a while.
+async fn check_images(images: &[String], reachable: bool) -> Result<(), Rejection> {
+    let mut urls = malformed_urls(images);
+    if urls.is_empty() && reachable {
+        urls = unreachable_urls(images).await;
+    }
+    if urls.is_empty() {
+        Ok(())
+    } else {
+        Err(warp::reject::custom(InvalidImages { urls }))
+    }
 }
 
 fn create_new_game(
     lock: &mut RwLockWriteGuard<MemoryStore>,
-    id: String,
-) -> Result<Json, Rejection> {
-    if lock.game.is_some() {
+    query: CreateQuery,
+    images: Option<Vec<String>>,
+) -> Result<String, Rejection> {
+    let id = query.id.clone();
+    if !valid_id(&id) || query.reveal_ms.is_some_and(|ms| ms > MAX_REVEAL_MS) {
+        return Err(warp::reject::custom(InvalidInput));
+    }
+    if lock.games.contains_key(&id) {
         return Err(warp::reject::custom(AlreadyExists));
     }
-    lock.game = Some(Memory::new(id.clone()));
-    println!("Created game with id: {}", id);
-    Ok(warp::reply::json(&"Success!"))
+    let unfinished = lock
+        .games
+        .values()
+        .filter(|game| !matches!(game.state, GameState::Finished))
+        .count();
+    if lock.max_games > 0 && unfinished >= lock.max_games {
+        return Err(warp::reject::custom(TooManyGames));
+    }
+
+    let config = game_config(query, images, lock.defaults.clone())?;
+    let game = Memory::with_config(id.clone(), config)?;
+    let admin_token = game.admin_token.clone();
+    lock.insert_game(game);
+    lock.save();
+    info!(game = %id, "Game created");
+    Ok(admin_token)
+}
+
+/// Overrides the store's defaults in `config` with whatever the creator
+/// asked for.
+fn game_config(
+    query: CreateQuery,
+    images: Option<Vec<String>>,
+    mut config: GameConfig,
+) -> Result<GameConfig, Rejection> {
+    if let Some(layout) = query.layout {
+        let layout = parse_layout(&layout).ok_or(warp::reject::custom(InvalidBoardSize))?;
+        config = config.layout(layout);
+    } else if query.columns.is_some() || query.rows.is_some() {
+        let columns = query.columns.unwrap_or(config.columns);
+        let rows = query.rows.unwrap_or(config.rows);
+        config = config.board(columns, rows);
+    }
+    if let Some(images) = images {
+        config = config.images(images);
+    }
+    if let Some(seed) = query.seed {
+        config = config.seed(seed);
+    }
+    if let Some(match_size) = query.match_size {
+        config = config.match_size(match_size);
+    }
+    if let Some(max_players) = query.max_players {
+        config = config.max_players(max_players);
+    }
+    if let Some(streak_bonus) = query.streak_bonus {
+        config = config.streak_bonus(streak_bonus);
+    }
+    if let Some(mismatch_penalty) = query.mismatch_penalty {
+        config = config.mismatch_penalty(mismatch_penalty);
+    }
+    if let Some(practice) = query.practice {
+        config = config.practice(practice);
+    }
+    if let Some(target_matches) = query.target_matches {
+        config = config.target_matches(target_matches);
+    }
+    if let Some(peeks) = query.peeks {
+        config = config.peeks(peeks);
+    }
+    if let Some(reveal_ms) = query.reveal_ms {
+        config = config.reveal_ms(reveal_ms);
+    }
+    if let Some(turn_mode) = query.turn_mode {
+        config = config.turn_mode(turn_mode);
+    }
+    let streak = (config.streak_bonus > 0).then_some(Scoring::Streak);
+    if let Some(scoring) = query.scoring.or(streak) {
+        config = config.scoring(scoring);
+    }
+    Ok(config)
+}
+
+async fn update_leaderboard(game: &mut Memory) {
+    let res = LeaderboardResponse::from(&game.players.values().collect());
+    game.broadcast("leaderboard", res).await;
+}
+
+async fn update_lobby_status(game: &mut Memory) {
+    let res = LobbyStatusResponse {
+        ready_count: game.players.values().filter(|p| p.ready).count(),
+        total: game.players.len(),
+    };
+    game.broadcast("lobbyStatus", res).await;
+}
+
+fn schedule_mismatch_hide(
+    store: Store,
+    id: String,
+    turn: usize,
+    delay: Duration,
+    timeout: Duration,
+) {
+    tokio::spawn(async move {
+        tokio::time::sleep(delay).await;
+
+        let mut lock = store.write().await;
+        let Ok(game) = lock.get_game_mut(&id) else {
+            return;
+        };
+        match game.state {
+            GameState::Running if game.has_pending_mismatch() && game.turn_number() == turn => (),
+            _ => return,
+        }
+
+        let delays = game.skip_turn().await;
+        update_leaderboard(game).await;
+        let turn = game.turn_number();
+        schedule_unflips(store.clone(), id.clone(), turn, delays);
+        lock.save();
+        drop(lock);
+
+        schedule_turn_timeout(store, id, turn, timeout);
+    });
+}
+
+/// Turns the cards of a lost turn back for slower players, once each delay
+/// `Memory::skip_turn` returned is over.
+fn schedule_unflips(store: Store, id: String, turn: usize, delays: Vec<Duration>) {
+    for delay in delays {
+        let store = store.clone();
+        let id = id.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            let mut lock = store.write().await;
+            if let Ok(game) = lock.get_game_mut(&id) {
+                game.release_unflips(turn, delay).await;
+            }
+        });
+    }
+}
+
+/// Tells every listener that the server is going away, saves the games and
+/// closes all event streams so the server can finish its connections.
+pub async fn shutdown(store: Store) {
+    let mut lock = store.write().await;
+    for game in lock.games.values_mut() {
+        game.broadcast("serverShutdown", "Server is shutting down")
+            .await;
+        game.disconnect_all();
+    }
+    lock.save();
+    info!(games = lock.games.len(), "Saved games for shutdown");
+}
+
+/// Periodically removes games that have had no open streams and no
+/// activity for `ttl`. A zero `ttl` disables reaping.
+pub fn spawn_idle_reaper(store: Store, ttl: Duration) {
+    if ttl.is_zero() {
+        return;
+    }
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(ttl.min(REAP_INTERVAL));
+        loop {
+            interval.tick().await;
+
+            let mut lock = store.write().await;
+            let idle: Vec<String> = lock
+                .games
+                .values()
+                .filter(|game| game.is_idle(ttl))
+                .map(|game| game.id.clone())
+                .collect();
+            if idle.is_empty() {
+                continue;
+            }
+
+            for id in idle {
+                let game = lock.remove_game(&id).unwrap();
+                let reason = if game.players.is_empty() {
+                    "nobody joined"
+                } else {
+                    "all players disconnected"
+                };
+                info!(
+                    game = %id,
+                    reason,
+                    idle_secs = game.last_activity.elapsed().as_secs(),
+                    "Reaped idle game"
+                );
+                lock.notify_admins("gameDeleted", GameDeletedResponse { id })
+                    .await;
+            }
+            lock.save();
+        }
+    });
+}
+
+/// Periodically removes players whose stream has been closed for longer than
+/// `grace`, passing the turn on if it was theirs. A zero `grace` keeps
+/// disconnected players around for good.
+pub fn spawn_disconnect_reaper(store: Store, grace: Duration) {
+    if grace.is_zero() {
+        return;
+    }
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(grace.min(DISCONNECT_CHECK_INTERVAL));
+        loop {
+            interval.tick().await;
+
+            let mut lock = store.write().await;
+            let timeout = lock.turn_timeout;
+            let countdown = lock.countdown;
+            let mut removed = Vec::new();
+            for game in lock.games.values_mut() {
+                for token in game.disconnected_players(grace) {
+                    if let Some(name) =
+                        remove_player(game, &token, &store, countdown, timeout).await
+                    {
+                        info!(game = %game.id, "Removed player after disconnect grace period");
+                        let id = game.id.clone();
+                        removed.push(AdminPlayerResponse { id, name });
+                    }
+                }
+            }
+            if removed.is_empty() {
+                continue;
+            }
+            for res in removed {
+                lock.notify_admins("playerLeft", res).await;
+            }
+            lock.save();
+        }
+    });
 }
 
-async fn update_leaderboard(players: Vec<&Player>) {
-    let res = LeaderboardResponse::from(&players);
-    broadcast_sse("leaderboard", res, players).await;
+/// Starts a lobby once `timeout` has passed since the timer was armed,
+/// treating players that never readied as ready. `lobbyTimeout` announces
+/// the seconds left `LOBBY_WARNING` before. Lobbies with too few players
+/// wait another round. Starting normally or deleting the game ends the timer.
+fn schedule_lobby_timeout(store: Store, id: String, armed: Instant, timeout: Duration) {
+    let warning = LOBBY_WARNING.min(timeout);
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(timeout - warning).await;
+            let warned = {
+                let mut lock = store.write().await;
+                let Ok(game) = lock.get_game_mut(&id) else {
+                    return;
+                };
+                match game.state {
+                    GameState::Lobby if game.is_lobby_timer(armed) => (),
+                    _ => return,
+                }
+                let warned = game.has_enough_players();
+                if warned {
+                    game.broadcast("lobbyTimeout", warning.as_secs()).await;
+                }
+                warned
+            };
+
+            tokio::time::sleep(warning).await;
+            let mut lock = store.write().await;
+            let countdown = lock.countdown;
+            let turn_timeout = lock.turn_timeout;
+            let Ok(game) = lock.get_game_mut(&id) else {
+                return;
+            };
+            match game.state {
+                GameState::Lobby if game.is_lobby_timer(armed) => (),
+                _ => return,
+            }
+            if !warned || !game.has_enough_players() {
+                continue;
+            }
+
+            game.ready_everyone();
+            start_game(game, &store, countdown, turn_timeout).await;
+            update_leaderboard(game).await;
+            update_lobby_status(game).await;
+            lock.save();
+            return;
+        }
+    });
+}
+
+pub fn schedule_turn_timeout(store: Store, id: String, turn: usize, timeout: Duration) {
+    if timeout.is_zero() {
+        return;
+    }
+
+    tokio::spawn(async move {
+        tokio::time::sleep(timeout).await;
+
+        let mut lock = store.write().await;
+        let Ok(game) = lock.get_game_mut(&id) else {
+            return;
+        };
+        match game.state {
+            GameState::Running if game.turn_number() == turn => (),
+            _ => return,
+        }
+
+        info!(game = %id, "Turn timed out");
+        if game.expire_turn() {
+            let delay = game.reveal_delay();
+            lock.save();
+            drop(lock);
+            schedule_mismatch_hide(store, id, turn, delay, timeout);
+            return;
+        }
+        let delays = game.skip_turn().await;
+        update_leaderboard(game).await;
+        let turn = game.turn_number();
+        schedule_unflips(store.clone(), id.clone(), turn, delays);
+        lock.save();
+        drop(lock);
+
+        schedule_turn_timeout(store, id, turn, timeout);
+    });
 }
@@ -1,13 +1,160 @@
+pub mod handler;
+pub mod routes;
+
 pub mod queries {
+    use crate::memory::{Scoring, TurnMode};
+
+    pub const MAX_NAME_LENGTH: usize = 32;
+    pub const MAX_ID_LENGTH: usize = 64;
+
+    /// Player names may use letters, digits, `-`, `_` and inner spaces.
+    pub fn valid_name(name: &str) -> bool {
+        !name.is_empty()
+            && name.chars().count() <= MAX_NAME_LENGTH
+            && name.trim() == name
+            && name
+                .chars()
+                .all(|c| c.is_alphanumeric() || c == ' ' || c == '-' || c == '_')
+    }
+
+    /// Game ids end up in URLs and log lines, so only ASCII letters,
+    /// digits, `-` and `_` are allowed.
+    pub fn valid_id(id: &str) -> bool {
+        !id.is_empty()
+            && id.len() <= MAX_ID_LENGTH
+            && id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    }
+
+    /// Why `key` is too weak to be the master key, if it is.
+    pub fn key_weakness(key: &str, min_length: usize) -> Option<&'static str> {
+        if key.chars().count() < min_length {
+            return Some("is shorter than MIN_KEY_LENGTH");
+        }
+        let distinct: std::collections::HashSet<char> = key.chars().collect();
+        let classes = [
+            key.chars().any(|c| c.is_ascii_lowercase()),
+            key.chars().any(|c| c.is_ascii_uppercase()),
+            key.chars().any(|c| c.is_ascii_digit()),
+            key.chars().any(|c| !c.is_ascii_alphanumeric()),
+        ];
+        if distinct.len() < 8 || classes.iter().filter(|c| **c).count() < 2 {
+            return Some("looks low-entropy");
+        }
+        None
+    }
+
+    /// Parses a layout like `4,4,2,4,4` into the number of cards in each
+    /// row. Empty rows are rejected.
+    pub fn parse_layout(layout: &str) -> Option<Vec<usize>> {
+        let rows = layout
+            .split(',')
+            .map(|row| row.trim().parse().ok().filter(|&n: &usize| n > 0))
+            .collect::<Option<Vec<_>>>()?;
+        Some(rows)
+    }
+
+    #[derive(serde::Deserialize)]
+    pub struct GameQuery {
+        pub id: String,
+    }
+
+    /// Sent as a JSON body, so the new key stays out of access logs.
+    #[derive(serde::Deserialize)]
+    pub struct RotateKeyBody {
+        pub key: String,
+    }
+
+    /// The token a new game admin got as `adminToken`, sent as a JSON body
+    /// like `RotateKeyBody`.
+    #[derive(serde::Deserialize)]
+    pub struct ClaimAdminBody {
+        pub admin_token: String,
+    }
+
     #[derive(serde::Deserialize)]
     pub struct CreateQuery {
         pub id: String,
+        pub columns: Option<usize>,
+        pub rows: Option<usize>,
+        pub match_size: Option<usize>,
+        /// Cards per row for boards that are not rectangular, see
+        /// `parse_layout`. Overrides `columns` and `rows`.
+        pub layout: Option<String>,
+        pub max_players: Option<usize>,
+        /// Seeds the card shuffle so the board can be reproduced.
+        pub seed: Option<u64>,
+        pub streak_bonus: Option<usize>,
+        pub mismatch_penalty: Option<usize>,
+        /// Lets a single player start the game on their own.
+        pub practice: Option<bool>,
+        /// Ends the game as soon as one player has this many matches.
+        pub target_matches: Option<usize>,
+        /// How often each player may see every card at once.
+        pub peeks: Option<usize>,
+        /// How long turned cards stay visible, overriding `REVEAL_DELAY`.
+        pub reveal_ms: Option<u64>,
+        pub turn_mode: Option<TurnMode>,
+        /// Defaults to `Streak` when a `streak_bonus` is given.
+        pub scoring: Option<Scoring>,
+    }
+
+    /// `CreateQuery` sent as a JSON body, which also carries the images.
+    #[derive(serde::Deserialize)]
+    pub struct CreateBody {
+        #[serde(flatten)]
+        pub query: CreateQuery,
+        pub images: Option<Vec<String>>,
+    }
+
+    #[derive(serde::Deserialize)]
+    pub struct GamesQuery {
+        /// Only list games that can still be joined.
+        pub lobby: Option<bool>,
+    }
+
+    #[derive(serde::Deserialize)]
+    pub struct LeaderboardQuery {
+        pub id: String,
+        pub top: Option<usize>,
     }
 
     #[derive(serde::Deserialize)]
     pub struct JoinQuery {
         pub id: String,
         pub name: String,
+        /// A color from the palette the player would like, if it is free.
+        pub color: Option<String>,
+        /// Keeps mismatched cards visible this many times longer for the
+        /// player, up to `MAX_REVEAL_MULTIPLIER`.
+        pub reveal_multiplier: Option<f64>,
+    }
+
+    #[derive(serde::Deserialize)]
+    pub struct KickQuery {
+        pub id: String,
+        pub name: String,
+    }
+
+    #[derive(serde::Deserialize)]
+    pub struct TransferAdminQuery {
+        pub id: String,
+        /// The player who becomes the game admin.
+        pub name: String,
+    }
+
+    #[derive(serde::Deserialize)]
+    pub struct ChatQuery {
+        pub id: String,
+        pub message: String,
+    }
+
+    #[derive(serde::Deserialize)]
+    pub struct AnnounceQuery {
+        /// Limits the announcement to one game instead of all of them.
+        pub id: Option<String>,
+        pub message: String,
     }
 
     #[derive(serde::Deserialize)]
@@ -15,52 +162,199 @@ pub mod queries {
         pub id: String,
         pub card: usize,
     }
+
+    /// An action sent by a client over its WebSocket.
+    #[derive(serde::Deserialize)]
+    #[serde(tag = "action", rename_all = "lowercase")]
+    pub enum WsAction {
+        Pick { card: usize },
+        Ready,
+    }
 }
 
 pub mod reply {
-    use crate::memory::{GameState, Player};
+    use crate::deck::CardFace;
+    use crate::memory::{Card, GameState, Memory, Player, Scoring, TurnMode};
+
+    pub type Players = Vec<(String, usize, bool, bool, usize, String)>;
 
-    pub type Players = Vec<(String, usize, bool, bool)>;
+    /// Orders players by points, highest first, then by name, so every
+    /// payload lists them the same way.
+    pub fn sort_players(players: &mut Players) {
+        players.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    }
 
     #[derive(serde::Serialize)]
     pub struct PickResponse {
-        pub img_path: String,
+        #[serde(flatten)]
+        pub face: CardFace,
         pub turn: bool,
     }
 
     #[derive(serde::Serialize)]
     pub struct HideResponse {
         pub card_id: usize,
+        /// The player who matched the card, and their score afterwards.
+        pub name: String,
+        pub points: usize,
+    }
+
+    #[derive(serde::Serialize)]
+    pub struct UnflipResponse {
+        pub card_id: usize,
+    }
+
+    #[derive(serde::Serialize)]
+    pub struct PlayerResponse {
+        pub name: String,
+        pub color: String,
+    }
+
+    #[derive(serde::Serialize)]
+    pub struct AdminChangedResponse {
+        pub name: String,
+    }
+
+    /// Sent only to the new game admin, whose client hands it to
+    /// `/claim_admin` to get the admin cookie.
+    #[derive(serde::Serialize)]
+    pub struct AdminTokenResponse {
+        pub admin_token: String,
+    }
+
+    #[derive(serde::Serialize)]
+    pub struct TurnResponse {
+        pub name: String,
     }
 
     #[derive(serde::Serialize)]
     pub struct GameOverResponse {
         pub game_state: GameState,
+        pub winners: Vec<String>,
+        pub leaderboard: Players,
+        pub duration_secs: u64,
+    }
+
+    /// A card as clients see it, with its face ready to load.
+    #[derive(serde::Serialize)]
+    pub struct CardResponse {
+        #[serde(flatten)]
+        pub face: CardFace,
+        pub flipped: bool,
+        pub gone: bool,
+    }
+
+    impl From<&Card> for CardResponse {
+        fn from(card: &Card) -> Self {
+            CardResponse {
+                face: CardFace::new(&card.img_path),
+                flipped: card.flipped,
+                gone: card.gone,
+            }
+        }
+    }
+
+    /// Every card face up, for admins checking a reported board and as the
+    /// `finalBoard` everyone gets once the game is over.
+    #[derive(serde::Serialize)]
+    pub struct BoardResponse<'a> {
+        pub columns: usize,
+        pub rows: usize,
+        pub layout: &'a Option<Vec<usize>>,
+        pub cards: Vec<CardResponse>,
+    }
+
+    /// Every card's face in board order, sent only to the peeking player.
+    #[derive(serde::Serialize)]
+    pub struct PeekResponse {
+        pub faces: Vec<CardFace>,
+        /// How long the client should keep the cards revealed.
+        pub reveal_ms: u64,
+    }
+
+    #[derive(serde::Serialize)]
+    pub struct AnnouncementResponse {
+        pub message: String,
+        pub timestamp: u64,
+    }
+
+    #[derive(serde::Serialize)]
+    pub struct PingResponse {
+        pub id: String,
+        pub player_count: usize,
+        pub state: GameState,
+    }
+
+    #[derive(serde::Serialize)]
+    pub struct GameStateResponse {
+        pub game_state: GameState,
+    }
+
+    #[derive(serde::Serialize)]
+    pub struct GameDeletedResponse {
+        pub id: String,
+    }
+
+    /// A player joining, leaving or being kicked, on the admin stream.
+    #[derive(serde::Serialize)]
+    pub struct AdminPlayerResponse {
+        pub id: String,
+        pub name: String,
+    }
+
+    #[derive(serde::Serialize)]
+    pub struct AdminGameOverResponse {
+        pub id: String,
+        pub winners: Vec<String>,
+        pub leaderboard: Players,
+        pub duration_secs: u64,
     }
 
     #[derive(serde::Serialize)]
     pub struct InitResponse {
         pub game_state: GameState,
         pub ready: bool,
-        pub flipped: Vec<(usize, String)>,
+        /// The requesting player's own standing. Zero and `None` for
+        /// spectators and broadcasts.
+        pub points: usize,
+        pub turn: bool,
+        pub rank: Option<usize>,
+        pub flipped: Vec<(usize, CardFace)>,
         pub hidden: Vec<usize>,
         pub players: Players,
+        pub columns: usize,
+        pub rows: usize,
+        /// Cards per row when the board is not a `columns` by `rows` grid.
+        pub layout: Option<Vec<usize>>,
+        /// Cards still on the board, always `total - hidden.len()`.
+        pub remaining: usize,
+        pub total: usize,
     }
 
     impl InitResponse {
+        /// Takes the board shape and state from `game` and the standing
+        /// from `player`, if the state is for one.
         pub fn from(
-            game_state: GameState,
-            ready: bool,
-            flipped: Vec<(usize, String)>,
+            game: &Memory,
+            player: Option<&Player>,
+            flipped: Vec<(usize, CardFace)>,
             hidden: Vec<usize>,
             players: Players,
         ) -> Self {
             Self {
-                game_state,
-                ready,
+                game_state: game.state,
+                ready: player.is_some_and(|p| p.ready),
+                points: player.map_or(0, |p| p.points),
+                turn: player.is_some_and(|p| p.turn),
+                rank: player.map(|p| game.rank(p.points)),
+                remaining: game.size - hidden.len(),
+                total: game.size,
                 flipped,
                 hidden,
                 players,
+                columns: game.columns,
+                rows: game.rows,
+                layout: game.layout.clone(),
             }
         }
     }
@@ -68,21 +362,140 @@ pub mod reply {
     #[derive(serde::Serialize)]
     pub struct FlipResponse {
         pub card_id: usize,
-        pub img_path: String,
+        #[serde(flatten)]
+        pub face: CardFace,
+        /// How long a mismatched pair stays visible before the server
+        /// flips it back.
+        pub reveal_ms: u64,
+        /// The player who picked the card.
+        pub name: String,
+    }
+
+    #[derive(serde::Serialize)]
+    pub struct ChatResponse {
+        pub name: String,
+        pub message: String,
+        pub timestamp: u64,
+    }
+
+    #[derive(serde::Serialize)]
+    pub struct GameHealth {
+        pub id: String,
+        pub game_state: GameState,
+        pub connected_players: usize,
+    }
+
+    #[derive(serde::Serialize)]
+    pub struct HealthResponse {
+        pub games: Vec<GameHealth>,
+    }
+
+    #[derive(serde::Serialize)]
+    pub struct GameSummary {
+        pub id: String,
+        pub state: GameState,
+        pub player_count: usize,
+        pub max_players: usize,
+    }
+
+    /// The effective settings of one game, defaults and server-wide values
+    /// included.
+    #[derive(serde::Serialize)]
+    pub struct ConfigResponse {
+        pub columns: usize,
+        pub rows: usize,
+        pub layout: Option<Vec<usize>>,
+        pub size: usize,
+        pub match_size: usize,
+        pub max_players: usize,
+        /// Zero when turns never time out.
+        pub turn_timeout_secs: u64,
+        pub reveal_ms: u64,
+        pub countdown_secs: u64,
+        pub streak_bonus: usize,
+        pub mismatch_penalty: usize,
+        pub practice: bool,
+        pub target_matches: Option<usize>,
+        pub peeks: usize,
+        pub turn_mode: TurnMode,
+        pub scoring: Scoring,
+    }
+
+    #[derive(serde::Serialize)]
+    pub struct GamesResponse {
+        pub games: Vec<GameSummary>,
+    }
+
+    #[derive(serde::Serialize)]
+    pub struct RankedPlayer {
+        pub rank: usize,
+        pub name: String,
+        pub points: usize,
+        pub streak: usize,
+    }
+
+    #[derive(serde::Serialize)]
+    pub struct RankingResponse {
+        pub players: Vec<RankedPlayer>,
+    }
+
+    impl RankingResponse {
+        /// Ranks `standings`, which must already be sorted by points. Tied
+        /// players share a rank and the next rank is skipped.
+        pub fn from(standings: Players, top: Option<usize>) -> Self {
+            let mut players: Vec<RankedPlayer> = Vec::with_capacity(standings.len());
+            for (i, (name, points, _, _, streak, _)) in standings.into_iter().enumerate() {
+                let rank = match players.last() {
+                    Some(prev) if prev.points == points => prev.rank,
+                    _ => i + 1,
+                };
+                players.push(RankedPlayer {
+                    rank,
+                    name,
+                    points,
+                    streak,
+                });
+            }
+            if let Some(top) = top {
+                players.truncate(top);
+            }
+            Self { players }
+        }
+    }
+
+    #[derive(serde::Serialize)]
+    pub struct LobbyStatusResponse {
+        pub ready_count: usize,
+        pub total: usize,
     }
 
     #[derive(serde::Serialize)]
     pub struct LeaderboardResponse {
         pub players: Players,
+        /// Who just found a match, so clients can highlight them. Only set
+        /// on updates that follow a pick.
+        pub last_scorer: Option<String>,
     }
 
     impl LeaderboardResponse {
         pub fn from(players: &Vec<&Player>) -> Self {
+            let mut players: Players = players
+                .iter()
+                .map(|p| {
+                    (
+                        p.name.clone(),
+                        p.points,
+                        p.ready,
+                        p.turn,
+                        p.current_streak,
+                        p.color.clone(),
+                    )
+                })
+                .collect();
+            sort_players(&mut players);
             Self {
-                players: players
-                    .into_iter()
-                    .map(|p| (p.name.clone(), p.points, p.ready, p.turn))
-                    .collect(),
+                players,
+                last_scorer: None,
             }
         }
     }
@@ -91,6 +504,8 @@ pub mod reply {
 pub mod reject {
     use std::convert::Infallible;
 
+    use tracing::{error, warn};
+    use warp::http::StatusCode;
     use warp::{reject, Rejection, Reply};
 
     #[derive(Debug)]
@@ -105,6 +520,10 @@ pub mod reject {
     pub struct InvalidMasterKey;
     impl reject::Reject for InvalidMasterKey {}
 
+    #[derive(Debug)]
+    pub struct InvalidGameAdmin;
+    impl reject::Reject for InvalidGameAdmin {}
+
     #[derive(Debug)]
     pub struct InvalidCard;
     impl reject::Reject for InvalidCard {}
@@ -113,6 +532,10 @@ pub mod reject {
     pub struct AlreadyExists;
     impl reject::Reject for AlreadyExists {}
 
+    #[derive(Debug)]
+    pub struct NameTaken;
+    impl reject::Reject for NameTaken {}
+
     #[derive(Debug)]
     pub struct AlreadyRunning;
     impl reject::Reject for AlreadyRunning {}
@@ -121,209 +544,1221 @@ pub mod reject {
     pub struct NotYourTurn;
     impl reject::Reject for NotYourTurn {}
 
+    #[derive(Debug)]
+    pub struct NotInLobby;
+    impl reject::Reject for NotInLobby {}
+
     #[derive(Debug)]
     pub struct NotYetRunning;
     impl reject::Reject for NotYetRunning {}
 
+    #[derive(Debug)]
+    pub struct GamePaused;
+    impl reject::Reject for GamePaused {}
+
     #[derive(Debug)]
     pub struct AlreadyFlipped;
     impl reject::Reject for AlreadyFlipped {}
 
-    pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible> {
-        if err.find::<InvalidToken>().is_some() {
-            eprintln!("Invalid token");
-            return Ok(warp::reply::with_status(
-                "Invalid token",
-                warp::http::StatusCode::UNAUTHORIZED,
-            ));
-        }
+    #[derive(Debug)]
+    pub struct TooManyFlipped;
+    impl reject::Reject for TooManyFlipped {}
 
-        if err.find::<InvalidMasterKey>().is_some() {
-            eprintln!("Invalid master key");
-            return Ok(warp::reply::with_status(
-                "Invalid master key",
-                warp::http::StatusCode::UNAUTHORIZED,
-            ));
-        }
+    #[derive(Debug)]
+    pub struct GameFull;
+    impl reject::Reject for GameFull {}
 
-        if err.find::<AlreadyExists>().is_some() {
-            eprintln!("Game already exists");
-            return Ok(warp::reply::with_status(
-                "Game already exists",
-                warp::http::StatusCode::CONFLICT,
-            ));
-        }
+    #[derive(Debug)]
+    pub struct NotEnoughPlayers;
+    impl reject::Reject for NotEnoughPlayers {}
 
-        if err.find::<NoGameExists>().is_some() {
-            eprintln!("No game exists");
-            return Ok(warp::reply::with_status(
-                "No game exists",
-                warp::http::StatusCode::NOT_FOUND,
-            ));
-        }
+    #[derive(Debug)]
+    pub struct PlayerNotFound;
+    impl reject::Reject for PlayerNotFound {}
 
-        eprintln!("Unhandled rejection: {:?}", err);
-        Ok(warp::reply::with_status(
-            "Internal server error",
-            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
-        ))
-    }
-}
+    #[derive(Debug)]
+    pub struct InvalidMessage;
+    impl reject::Reject for InvalidMessage {}
 
-pub mod sse_utils {
-    use std::convert::Infallible;
+    #[derive(Debug)]
+    pub struct TooFast;
+    impl reject::Reject for TooFast {}
 
-    use warp::sse::Event;
+    #[derive(Debug)]
+    pub struct InvalidInput;
+    impl reject::Reject for InvalidInput {}
 
-    use crate::memory::Player;
+    #[derive(Debug)]
+    pub struct BodyTooLarge;
+    impl reject::Reject for BodyTooLarge {}
 
-    pub async fn broadcast_sse(
-        event_name: &str,
-        reply: impl serde::Serialize,
-        players: Vec<&Player>,
-    ) {
-        for player in players {
-            send_sse(event_name, &reply, player.sender.as_ref()).await;
-        }
-    }
+    /// A new master key that `key_weakness` finds fault with.
+    #[derive(Debug)]
+    pub struct WeakKey;
+    impl reject::Reject for WeakKey {}
 
-    pub async fn send_sse(
-        event_name: &str,
-        reply: &impl serde::Serialize,
-        channel: Option<&tokio::sync::mpsc::Sender<Result<Event, Infallible>>>,
-    ) {
-        if let Some(sender) = channel {
-            sender
-                .send(Ok(Event::default()
-                    .event(event_name)
-                    .json_data(reply)
-                    .unwrap_or(Event::default().comment("hello"))))
-                .await
-                .unwrap();
-        }
-    }
-}
+    #[derive(Debug)]
+    pub struct NothingToUndo;
+    impl reject::Reject for NothingToUndo {}
 
-pub mod memory {
-    use std::{collections::HashMap, convert::Infallible, sync::Arc};
+    /// The player already took a card back this turn.
+    #[derive(Debug)]
+    pub struct UndoUsed;
+    impl reject::Reject for UndoUsed {}
 
-    use rand::{seq::SliceRandom, thread_rng, Rng};
-    use tokio::sync::RwLock;
-    use warp::{reply::Json, sse::Event, Rejection};
+    /// Cards of the last turn are still showing and about to be hidden.
+    #[derive(Debug)]
+    pub struct ResolvingMatch;
+    impl reject::Reject for ResolvingMatch {}
 
-    use crate::{
-        icons::LINKS,
-        reject::{AlreadyFlipped, InvalidCard},
-        reply::{FlipResponse, GameOverResponse, HideResponse, InitResponse},
-        sse_utils::broadcast_sse,
-    };
+    /// One address sent too many create or join requests.
+    #[derive(Debug)]
+    pub struct TooManyRequests;
+    impl reject::Reject for TooManyRequests {}
 
-    pub type Store = Arc<RwLock<MemoryStore>>;
+    #[derive(Debug)]
+    pub struct TooManyGames;
+    impl reject::Reject for TooManyGames {}
 
-    #[derive(Clone)]
-    pub struct Card {
-        pub img_path: String,
-        pub flipped: bool,
-        pub gone: bool,
-    }
+    #[derive(Debug)]
+    pub struct PeeksDisabled;
+    impl reject::Reject for PeeksDisabled {}
 
-    impl Card {
-        pub fn new(img_path: String) -> Self {
-            Card {
-                img_path,
-                flipped: false,
-                gone: false,
-            }
-        }
+    #[derive(Debug)]
+    pub struct NoPeeksLeft;
+    impl reject::Reject for NoPeeksLeft {}
+
+    #[derive(Debug)]
+    pub struct InvalidBoardSize;
+    impl reject::Reject for InvalidBoardSize {}
+
+    #[derive(Debug)]
+    pub struct NotEnoughImages {
+        pub needed: usize,
+        pub supplied: usize,
     }
+    impl reject::Reject for NotEnoughImages {}
 
-    pub struct Player {
-        pub name: String,
-        pub points: usize,
-        pub turn: bool,
-        pub ready: bool,
-        pub sender: Option<tokio::sync::mpsc::Sender<Result<Event, Infallible>>>,
+    /// Custom deck images that are malformed or, when checked, unreachable.
+    #[derive(Debug)]
+    pub struct InvalidImages {
+        pub urls: Vec<String>,
     }
+    impl reject::Reject for InvalidImages {}
 
-    impl Player {
-        pub fn new(name: String) -> Self {
-            Player {
-                name,
-                points: 0,
-                turn: false,
-                ready: false,
-                sender: None,
-            }
-        }
+    #[derive(serde::Serialize)]
+    pub struct ErrorResponse {
+        pub error: &'static str,
+        pub code: &'static str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub invalid_urls: Option<Vec<String>>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub endpoints: Option<&'static [&'static str]>,
     }
 
-    #[derive(serde::Serialize, Clone, Copy)]
-    pub enum GameState {
-        Lobby,
+    /// The status, machine-readable code and message a rejection is
+    /// answered with. Anything unknown is an internal error.
+    pub fn describe(err: &Rejection) -> (StatusCode, &'static str, &'static str) {
+        if err.find::<InvalidToken>().is_some() {
+            return (StatusCode::UNAUTHORIZED, "INVALID_TOKEN", "Invalid token");
+        }
+        if err.find::<InvalidMasterKey>().is_some() {
+            return (
+                StatusCode::UNAUTHORIZED,
+                "INVALID_MASTER_KEY",
+                "Invalid master key",
+            );
+        }
+        if err.find::<InvalidGameAdmin>().is_some() {
+            return (
+                StatusCode::UNAUTHORIZED,
+                "INVALID_GAME_ADMIN",
+                "Invalid game admin token",
+            );
+        }
+        if err.find::<AlreadyExists>().is_some() {
+            return (
+                StatusCode::CONFLICT,
+                "ALREADY_EXISTS",
+                "Game already exists",
+            );
+        }
+        if err.find::<NameTaken>().is_some() {
+            return (StatusCode::CONFLICT, "NAME_TAKEN", "Name already taken");
+        }
+        if err.find::<NoGameExists>().is_some() {
+            return (StatusCode::NOT_FOUND, "NO_GAME_EXISTS", "No game exists");
+        }
+        if err.find::<NotInLobby>().is_some() {
+            return (
+                StatusCode::CONFLICT,
+                "NOT_IN_LOBBY",
+                "Game is not in the lobby",
+            );
+        }
+        if err.find::<GamePaused>().is_some() {
+            return (StatusCode::CONFLICT, "GAME_PAUSED", "Game is paused");
+        }
+        if err.find::<AlreadyRunning>().is_some() {
+            return (
+                StatusCode::CONFLICT,
+                "ALREADY_RUNNING",
+                "Game is already running",
+            );
+        }
+        if err.find::<NotYetRunning>().is_some() {
+            return (
+                StatusCode::CONFLICT,
+                "NOT_YET_RUNNING",
+                "Game is not running",
+            );
+        }
+        if err.find::<NotYourTurn>().is_some() {
+            return (StatusCode::FORBIDDEN, "NOT_YOUR_TURN", "Not your turn");
+        }
+        if err.find::<InvalidCard>().is_some() {
+            return (StatusCode::BAD_REQUEST, "INVALID_CARD", "Invalid card");
+        }
+        if err.find::<AlreadyFlipped>().is_some() {
+            return (
+                StatusCode::CONFLICT,
+                "ALREADY_FLIPPED",
+                "Card is already flipped",
+            );
+        }
+        if err.find::<TooManyFlipped>().is_some() {
+            return (
+                StatusCode::CONFLICT,
+                "TOO_MANY_FLIPPED",
+                "Too many cards flipped",
+            );
+        }
+        if err.find::<GameFull>().is_some() {
+            return (StatusCode::CONFLICT, "GAME_FULL", "Game is full");
+        }
+        if err.find::<NotEnoughPlayers>().is_some() {
+            return (
+                StatusCode::CONFLICT,
+                "NOT_ENOUGH_PLAYERS",
+                "Not enough players",
+            );
+        }
+        if err.find::<PlayerNotFound>().is_some() {
+            return (
+                StatusCode::NOT_FOUND,
+                "PLAYER_NOT_FOUND",
+                "Player not found",
+            );
+        }
+        if err.find::<InvalidMessage>().is_some() {
+            return (
+                StatusCode::BAD_REQUEST,
+                "INVALID_MESSAGE",
+                "Invalid message",
+            );
+        }
+        if err.find::<TooFast>().is_some() {
+            return (StatusCode::TOO_MANY_REQUESTS, "TOO_FAST", "Too many picks");
+        }
+        if err.find::<TooManyRequests>().is_some() {
+            return (
+                StatusCode::TOO_MANY_REQUESTS,
+                "TOO_MANY_REQUESTS",
+                "Too many requests",
+            );
+        }
+        if err.find::<InvalidInput>().is_some() {
+            return (StatusCode::BAD_REQUEST, "INVALID_INPUT", "Invalid input");
+        }
+        if err.find::<WeakKey>().is_some() {
+            return (StatusCode::BAD_REQUEST, "WEAK_KEY", "Key is too weak");
+        }
+        if err.find::<BodyTooLarge>().is_some() {
+            return (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "BODY_TOO_LARGE",
+                "Payload too large",
+            );
+        }
+        if err.find::<ResolvingMatch>().is_some() {
+            return (
+                StatusCode::CONFLICT,
+                "RESOLVING_MATCH",
+                "Cards are still being turned back",
+            );
+        }
+        if err.find::<NothingToUndo>().is_some() {
+            return (StatusCode::CONFLICT, "NOTHING_TO_UNDO", "Nothing to undo");
+        }
+        if err.find::<UndoUsed>().is_some() {
+            return (
+                StatusCode::CONFLICT,
+                "UNDO_USED",
+                "Only one card can be taken back per turn",
+            );
+        }
+        if err.find::<TooManyGames>().is_some() {
+            return (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "TOO_MANY_GAMES",
+                "Too many games",
+            );
+        }
+        if err.find::<PeeksDisabled>().is_some() {
+            return (
+                StatusCode::FORBIDDEN,
+                "PEEKS_DISABLED",
+                "Peeks are disabled for this game",
+            );
+        }
+        if err.find::<NoPeeksLeft>().is_some() {
+            return (StatusCode::CONFLICT, "NO_PEEKS_LEFT", "No peeks left");
+        }
+        if err.find::<InvalidBoardSize>().is_some() {
+            return (
+                StatusCode::BAD_REQUEST,
+                "INVALID_BOARD_SIZE",
+                "Invalid board size",
+            );
+        }
+        if err.find::<NotEnoughImages>().is_some() {
+            return (
+                StatusCode::BAD_REQUEST,
+                "NOT_ENOUGH_IMAGES",
+                "Not enough distinct images for this board size",
+            );
+        }
+        if err.find::<InvalidImages>().is_some() {
+            return (
+                StatusCode::BAD_REQUEST,
+                "INVALID_IMAGES",
+                "Some image URLs are invalid or unreachable",
+            );
+        }
+        if err.find::<warp::cors::CorsForbidden>().is_some() {
+            return (
+                StatusCode::FORBIDDEN,
+                "ORIGIN_NOT_ALLOWED",
+                "Origin not allowed",
+            );
+        }
+        if err.find::<reject::MissingCookie>().is_some() {
+            return (StatusCode::UNAUTHORIZED, "MISSING_COOKIE", "Missing cookie");
+        }
+        // Without any Cookie header warp reports the header, not the cookie.
+        if let Some(invalid) = err.find::<reject::InvalidHeader>() {
+            if invalid.name().eq_ignore_ascii_case("cookie") {
+                return (StatusCode::UNAUTHORIZED, "MISSING_COOKIE", "Missing cookie");
+            }
+            return (StatusCode::BAD_REQUEST, "INVALID_HEADER", "Invalid header");
+        }
+        if err.find::<reject::MissingHeader>().is_some() {
+            return (StatusCode::BAD_REQUEST, "MISSING_HEADER", "Missing header");
+        }
+        if err.find::<reject::InvalidQuery>().is_some() {
+            return (StatusCode::BAD_REQUEST, "INVALID_QUERY", "Invalid query");
+        }
+        if err.find::<reject::PayloadTooLarge>().is_some() {
+            return (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "BODY_TOO_LARGE",
+                "Payload too large",
+            );
+        }
+        if err.find::<reject::LengthRequired>().is_some() {
+            return (
+                StatusCode::LENGTH_REQUIRED,
+                "LENGTH_REQUIRED",
+                "Content-Length required",
+            );
+        }
+        if err.find::<reject::UnsupportedMediaType>().is_some() {
+            return (
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "UNSUPPORTED_MEDIA_TYPE",
+                "Body must be JSON",
+            );
+        }
+        if err
+            .find::<warp::filters::body::BodyDeserializeError>()
+            .is_some()
+        {
+            return (StatusCode::BAD_REQUEST, "INVALID_BODY", "Invalid body");
+        }
+        if err.find::<reject::MethodNotAllowed>().is_some() {
+            return (
+                StatusCode::METHOD_NOT_ALLOWED,
+                "METHOD_NOT_ALLOWED",
+                "Method not allowed",
+            );
+        }
+        if err.is_not_found() {
+            return (StatusCode::NOT_FOUND, "NOT_FOUND", "Not found");
+        }
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "INTERNAL_ERROR",
+            "Internal server error",
+        )
+    }
+
+    pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible> {
+        let (status, code, message) = describe(&err);
+        if status.is_server_error() {
+            error!(rejection = ?err, "Unhandled rejection");
+        } else {
+            warn!(code, rejection = ?err, "{}", message);
+        }
+
+        let body = warp::reply::json(&error_response(&err));
+        Ok(warp::reply::with_status(body, status))
+    }
+
+    /// The body a rejection is answered with, over HTTP as well as on a
+    /// WebSocket.
+    pub fn error_response(err: &Rejection) -> ErrorResponse {
+        let (_, code, message) = describe(err);
+        ErrorResponse {
+            error: message,
+            code,
+            invalid_urls: err.find::<InvalidImages>().map(|err| err.urls.clone()),
+            endpoints: err.is_not_found().then_some(&crate::routes::ENDPOINTS[..]),
+        }
+    }
+}
+
+pub mod sse_utils {
+    use crate::metrics;
+    use tracing::{debug, error};
+    use warp::{sse::Event, ws::Message};
+
+    /// A serialized game event that can be delivered over either SSE or a
+    /// WebSocket.
+    #[derive(Clone)]
+    pub struct GameEvent {
+        pub name: String,
+        pub data: serde_json::Value,
+        /// The position in the game's event log, for broadcasts only.
+        pub id: Option<u64>,
+    }
+
+    impl GameEvent {
+        /// Serializes `reply` into an event without an id.
+        pub fn new(event_name: &str, reply: &impl serde::Serialize) -> Result<Self, SendSseError> {
+            let data = serde_json::to_value(reply).map_err(|err| {
+                error!(event = event_name, error = %err, "Failed to serialize event");
+                SendSseError::Serialize(err)
+            })?;
+            Ok(GameEvent {
+                name: event_name.to_owned(),
+                data,
+                id: None,
+            })
+        }
+
+        pub fn into_sse(self) -> Event {
+            let event = Event::default()
+                .event(self.name)
+                .data(self.data.to_string());
+            match self.id {
+                Some(id) => event.id(id.to_string()),
+                None => event,
+            }
+        }
+
+        pub fn into_ws(self) -> Message {
+            let json = serde_json::json!({ "event": self.name, "data": self.data });
+            Message::text(json.to_string())
+        }
+    }
+
+    pub type EventSender = tokio::sync::mpsc::Sender<GameEvent>;
+
+    /// Why an event was not delivered.
+    #[derive(Debug)]
+    pub enum SendSseError {
+        /// The payload could not be turned into JSON, so nothing was sent.
+        Serialize(serde_json::Error),
+        /// The receiving stream has been closed.
+        Closed,
+    }
+
+    /// Sends the event to every channel and returns the ones that turned
+    /// out to be closed, so the caller can drop them.
+    pub async fn broadcast_sse(
+        event_name: &str,
+        reply: impl serde::Serialize,
+        channels: Vec<&EventSender>,
+    ) -> Vec<EventSender> {
+        match GameEvent::new(event_name, &reply) {
+            Ok(event) => broadcast_event(&event, channels).await,
+            // Nothing can be sent, so no channel is found to be closed.
+            Err(_) => Vec::new(),
+        }
+    }
+
+    /// Like `broadcast_sse`, for an event that is already serialized.
+    pub async fn broadcast_event(
+        event: &GameEvent,
+        channels: Vec<&EventSender>,
+    ) -> Vec<EventSender> {
+        metrics::increment(&metrics::BROADCASTS);
+        let mut closed = Vec::new();
+        for channel in channels {
+            if send_event(event.clone(), channel).await.is_err() {
+                closed.push(channel.clone());
+            }
+        }
+        closed
+    }
+
+    pub async fn send_sse(
+        event_name: &str,
+        reply: &impl serde::Serialize,
+        channel: Option<&EventSender>,
+    ) -> Result<(), SendSseError> {
+        if let Some(sender) = channel {
+            send_event(GameEvent::new(event_name, reply)?, sender).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn send_event(event: GameEvent, sender: &EventSender) -> Result<(), SendSseError> {
+        sender.send(event).await.map_err(|err| {
+            debug!(event = err.0.name, "Dropped event for a closed channel");
+            SendSseError::Closed
+        })
+    }
+}
+
+pub mod memory {
+    use std::{
+        collections::{HashMap, HashSet, VecDeque},
+        fs,
+        net::IpAddr,
+        path::{Path, PathBuf},
+        sync::Arc,
+        time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+    };
+
+    use rand::{rngs::StdRng, seq::SliceRandom, thread_rng, Rng, SeedableRng};
+    use tokio::sync::RwLock;
+    use tracing::{error, info};
+    use warp::{reply::Json, Rejection};
+
+    use crate::{
+        deck::CardFace,
+        icons::LINKS,
+        metrics,
+        reject::{
+            AlreadyFlipped, AlreadyRunning, InvalidBoardSize, InvalidCard, InvalidInput,
+            InvalidToken, NoGameExists, NoPeeksLeft, NotEnoughImages, NothingToUndo, PeeksDisabled,
+            ResolvingMatch, TooManyFlipped, UndoUsed,
+        },
+        reply::{
+            sort_players, BoardResponse, CardResponse, FlipResponse, GameOverResponse,
+            HideResponse, InitResponse, Players, TurnResponse, UnflipResponse,
+        },
+        sse_utils::{broadcast_event, broadcast_sse, send_event, EventSender, GameEvent},
+    };
+
+    pub type Store = Arc<RwLock<MemoryStore>>;
+
+    pub const DEFAULT_COLUMNS: usize = 9;
+    pub const DEFAULT_ROWS: usize = 6;
+    pub const DEFAULT_MATCH_SIZE: usize = 2;
+    pub const DEFAULT_MAX_PLAYERS: usize = 8;
+    /// The fewest players a game can be started with.
+    pub const MIN_PLAYERS: usize = 2;
+    pub const MAX_HISTORY: usize = 1000;
+    /// How many broadcasts a reconnecting client can catch up on.
+    pub const MAX_EVENT_LOG: usize = 256;
+    /// Beyond this many tracked addresses, those whose bucket is full again
+    /// are forgotten.
+    pub const MAX_RATE_LIMITED_ADDRS: usize = 1024;
+    pub const DEFAULT_REVEAL_MS: u64 = 1500;
+    pub const MAX_REVEAL_MS: u64 = 10_000;
+    pub const MAX_REVEAL_MULTIPLIER: f64 = 3.0;
+    /// How long after the first card of a set `TimedBonus` still pays
+    /// extra, one point less for every second that passes.
+    pub const TIMED_BONUS_SECS: u64 = 5;
+    /// Colors handed out to players in join order.
+    pub const PALETTE: [&str; 8] = [
+        "#e6194b", "#3cb44b", "#4363d8", "#f58231", "#911eb4", "#42d4f4", "#f032e6", "#bfef45",
+    ];
+
+    fn default_reveal_multiplier() -> f64 {
+        1.0
+    }
+
+    /// Seconds since the Unix epoch.
+    pub fn unix_timestamp() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    }
+
+    #[derive(Clone, serde::Serialize, serde::Deserialize)]
+    pub struct Card {
+        pub img_path: String,
+        pub flipped: bool,
+        pub gone: bool,
+    }
+
+    impl Card {
+        pub fn new(img_path: String) -> Self {
+            Card {
+                img_path,
+                flipped: false,
+                gone: false,
+            }
+        }
+    }
+
+    /// `unflipCard` broadcasts held back from one stream of a player who sees
+    /// mismatched cards for longer, each with the card it turns back.
+    struct HeldUnflips {
+        sender: EventSender,
+        turn: usize,
+        delay: Duration,
+        events: Vec<(usize, GameEvent)>,
+    }
+
+    #[derive(serde::Serialize, serde::Deserialize)]
+    pub struct Player {
+        pub name: String,
+        pub points: usize,
+        pub turn: bool,
+        pub ready: bool,
+        #[serde(default)]
+        pub color: String,
+        /// Matches in a row since the player last missed or lost the turn.
+        #[serde(default)]
+        pub current_streak: usize,
+        /// Matches found this game, unaffected by bonuses and penalties.
+        #[serde(default)]
+        pub matches: usize,
+        #[serde(default)]
+        pub peeks_left: usize,
+        /// Multiplies how long this player sees mismatched cards, capped at
+        /// `MAX_REVEAL_MULTIPLIER` (3.0). Meant as an accessibility option,
+        /// but it is an advantage: the player gets extra time to memorize
+        /// the cards that the others don't get.
+        #[serde(default = "default_reveal_multiplier")]
+        pub reveal_multiplier: f64,
+        /// One stream per open tab or connection of this player.
+        #[serde(skip)]
+        pub senders: Vec<EventSender>,
+        #[serde(skip)]
+        pub last_pick: Option<Instant>,
+        /// When the player's event stream closed, cleared on reconnect.
+        #[serde(skip)]
+        pub disconnected_at: Option<Instant>,
+    }
+
+    impl Player {
+        pub fn new(name: String, color: String) -> Self {
+            Player {
+                name,
+                points: 0,
+                turn: false,
+                ready: false,
+                color,
+                current_streak: 0,
+                matches: 0,
+                peeks_left: 0,
+                reveal_multiplier: 1.0,
+                senders: Vec::new(),
+                last_pick: None,
+                disconnected_at: None,
+            }
+        }
+
+        pub fn is_connected(&self) -> bool {
+            !self.senders.is_empty()
+        }
+
+        /// Adds a stream and returns whether the player was disconnected
+        /// until now.
+        pub fn add_sender(&mut self, sender: EventSender) -> bool {
+            self.senders.push(sender);
+            self.disconnected_at.take().is_some()
+        }
+
+        /// Forgets `sender` and returns whether that was the player's last
+        /// stream, which starts the disconnect grace period.
+        pub fn remove_sender(&mut self, sender: &EventSender) -> bool {
+            let before = self.senders.len();
+            self.senders.retain(|s| !s.same_channel(sender));
+            if self.senders.len() == before || self.is_connected() {
+                return false;
+            }
+            self.disconnected_at = Some(Instant::now());
+            true
+        }
+    }
+
+    #[derive(Clone, serde::Serialize, serde::Deserialize)]
+    pub struct MoveRecord {
+        pub player_name: String,
+        pub card_id: usize,
+        pub matched: bool,
+        pub timestamp: u64,
+    }
+
+    #[derive(serde::Serialize, serde::Deserialize, Clone, Copy)]
+    pub enum GameState {
+        Lobby,
+        /// Everyone is ready and the pre-start countdown is running.
+        Starting,
         Running,
+        Paused,
         Finished,
     }
 
+    /// Who gets the turn after the current player loses it.
+    #[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+    pub enum TurnMode {
+        /// Join order.
+        #[default]
+        Sequential,
+        /// Any other player, drawn anew every turn.
+        Random,
+        /// Join order backwards.
+        Reverse,
+    }
+
+    /// How many points a match is worth.
+    #[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+    pub enum Scoring {
+        /// One point per match.
+        #[default]
+        Classic,
+        /// One point plus whatever is left of `TIMED_BONUS_SECS` since the
+        /// first card of the set was turned.
+        TimedBonus,
+        /// One point plus `streak_bonus` for every earlier match in the
+        /// current streak.
+        Streak,
+    }
+
+    impl Scoring {
+        /// The points for a match found `elapsed` after its first card, by a
+        /// player with `streak` matches in a row before it.
+        pub fn points(self, streak: usize, streak_bonus: usize, elapsed: Duration) -> usize {
+            match self {
+                Scoring::Classic => 1,
+                Scoring::TimedBonus => {
+                    1 + TIMED_BONUS_SECS.saturating_sub(elapsed.as_secs()) as usize
+                }
+                Scoring::Streak => 1 + streak * streak_bonus,
+            }
+        }
+    }
+
+    /// The settings a game is created with. The defaults give a board of
+    /// pairs in `DEFAULT_COLUMNS` by `DEFAULT_ROWS` with the built-in images.
+    #[derive(Clone, Debug)]
+    pub struct GameConfig {
+        pub columns: usize,
+        pub rows: usize,
+        /// Cards per row, replacing `columns` and `rows` when set.
+        pub layout: Option<Vec<usize>>,
+        pub match_size: usize,
+        /// Card faces to use instead of `LINKS`.
+        pub images: Option<Vec<String>>,
+        pub seed: Option<u64>,
+        pub max_players: usize,
+        pub streak_bonus: usize,
+        pub mismatch_penalty: usize,
+        pub practice: bool,
+        pub target_matches: Option<usize>,
+        pub peeks: usize,
+        pub reveal_ms: u64,
+        pub turn_mode: TurnMode,
+        pub scoring: Scoring,
+    }
+
+    impl Default for GameConfig {
+        fn default() -> Self {
+            GameConfig {
+                columns: DEFAULT_COLUMNS,
+                rows: DEFAULT_ROWS,
+                layout: None,
+                match_size: DEFAULT_MATCH_SIZE,
+                images: None,
+                seed: None,
+                max_players: DEFAULT_MAX_PLAYERS,
+                streak_bonus: 0,
+                mismatch_penalty: 0,
+                practice: false,
+                target_matches: None,
+                peeks: 0,
+                reveal_ms: DEFAULT_REVEAL_MS,
+                turn_mode: TurnMode::Sequential,
+                scoring: Scoring::Classic,
+            }
+        }
+    }
+
+    impl GameConfig {
+        pub fn board(mut self, columns: usize, rows: usize) -> Self {
+            self.columns = columns;
+            self.rows = rows;
+            self.layout = None;
+            self
+        }
+
+        pub fn layout(mut self, layout: Vec<usize>) -> Self {
+            self.layout = Some(layout);
+            self
+        }
+
+        pub fn match_size(mut self, match_size: usize) -> Self {
+            self.match_size = match_size;
+            self
+        }
+
+        pub fn images(mut self, images: Vec<String>) -> Self {
+            self.images = Some(images);
+            self
+        }
+
+        pub fn seed(mut self, seed: u64) -> Self {
+            self.seed = Some(seed);
+            self
+        }
+
+        pub fn max_players(mut self, max_players: usize) -> Self {
+            self.max_players = max_players;
+            self
+        }
+
+        pub fn streak_bonus(mut self, streak_bonus: usize) -> Self {
+            self.streak_bonus = streak_bonus;
+            self
+        }
+
+        pub fn mismatch_penalty(mut self, mismatch_penalty: usize) -> Self {
+            self.mismatch_penalty = mismatch_penalty;
+            self
+        }
+
+        pub fn practice(mut self, practice: bool) -> Self {
+            self.practice = practice;
+            self
+        }
+
+        pub fn target_matches(mut self, target_matches: usize) -> Self {
+            self.target_matches = Some(target_matches);
+            self
+        }
+
+        pub fn peeks(mut self, peeks: usize) -> Self {
+            self.peeks = peeks;
+            self
+        }
+
+        pub fn reveal_ms(mut self, reveal_ms: u64) -> Self {
+            self.reveal_ms = reveal_ms;
+            self
+        }
+
+        pub fn turn_mode(mut self, turn_mode: TurnMode) -> Self {
+            self.turn_mode = turn_mode;
+            self
+        }
+
+        pub fn scoring(mut self, scoring: Scoring) -> Self {
+            self.scoring = scoring;
+            self
+        }
+    }
+
+    #[derive(serde::Serialize, serde::Deserialize)]
     pub struct Memory {
         pub id: String,
         pub players: HashMap<String, Player>,
         pub state: GameState,
         pub cards: Vec<Card>,
+        pub images: Vec<String>,
+        /// The bounding box of the board. For irregular boards `columns` is
+        /// the longest row.
+        pub columns: usize,
+        pub rows: usize,
+        /// Cards per row, only set for boards that are not rectangular.
+        #[serde(default)]
+        pub layout: Option<Vec<usize>>,
+        /// The number of cards on the board.
+        #[serde(default)]
+        pub size: usize,
+        /// How many cards with the same image make up a match.
+        pub match_size: usize,
+        pub max_players: usize,
+        /// Handed to the creator; authorizes deleting, restarting, kicking
+        /// from and reading the history of this game only.
+        #[serde(default)]
+        pub admin_token: String,
+        /// Extra points per earlier match in the current streak. Zero
+        /// disables the bonus.
+        #[serde(default)]
+        pub streak_bonus: usize,
+        /// Points taken from a player for every mismatch, never going
+        /// below zero.
+        #[serde(default)]
+        pub mismatch_penalty: usize,
+        /// Practice games can be started by a single player, who then gets
+        /// every turn back after a mismatch.
+        #[serde(default)]
+        pub practice: bool,
+        /// Ends the game once a player has this many matches, before the
+        /// board is cleared. `None` plays until every card is gone.
+        #[serde(default)]
+        pub target_matches: Option<usize>,
+        /// Peeks every player gets per game. Zero disables peeking.
+        #[serde(default)]
+        pub peeks: usize,
+        /// The card picked last, which may be turned back while its set is
+        /// incomplete.
+        #[serde(skip)]
+        last_flip: Option<usize>,
+        /// Who found the most recent match, until the turn passes on.
+        #[serde(skip)]
+        pub last_scorer: Option<String>,
+        /// Who won the finished game, empty until then.
+        #[serde(default)]
+        pub winners: Vec<String>,
+        /// When the game started, in seconds since the Unix epoch, so that
+        /// it survives a restart.
+        #[serde(default)]
+        pub started_at: Option<u64>,
+        /// How long the game ran, from start to game over. Zero until then.
+        #[serde(default)]
+        pub duration_secs: u64,
+        /// The most recent picks, oldest first, capped at `MAX_HISTORY`.
+        pub history: VecDeque<MoveRecord>,
+        /// The most recent broadcasts, oldest first, capped at
+        /// `MAX_EVENT_LOG`. Lost on restart, unlike the sequence number.
+        #[serde(skip)]
+        event_log: VecDeque<GameEvent>,
+        /// The id of the latest broadcast.
+        #[serde(default)]
+        event_seq: u64,
+        #[serde(skip)]
+        spectators: HashMap<String, EventSender>,
+        /// When a handler last touched this game. Restored games count as
+        /// active from the moment they are loaded.
+        #[serde(skip, default = "Instant::now")]
+        pub last_activity: Instant,
+        /// How long turned cards stay face up before a mismatch or an
+        /// expired turn hides them. Passed on to clients in `flipCard`.
+        #[serde(default = "default_reveal_ms")]
+        pub reveal_ms: u64,
+        #[serde(default)]
+        pub turn_mode: TurnMode,
+        #[serde(default)]
+        pub scoring: Scoring,
+        /// When the first card of the set currently face up was turned.
+        #[serde(skip)]
+        first_flip_at: Option<Instant>,
+        /// When the lobby timer was armed. Only the timer armed at this
+        /// moment may start the game.
+        #[serde(skip)]
+        lobby_timer: Option<Instant>,
+        /// Draws the starting player, random turns and the rematch boards.
+        /// Seeded together with the board, so a seeded game plays out the
+        /// same way again.
+        #[serde(skip, default = "StdRng::from_entropy")]
+        rng: StdRng,
+        /// Player tokens in join order. `current_turn` indexes into this,
+        /// since iterating `players` gives no stable order.
+        turn_order: Vec<String>,
         current_turn: usize,
+        turn_number: usize,
+        /// Set while cards of a lost turn stay visible, which locks the board
+        /// until `skip_turn` hides them.
+        mismatch: bool,
+        /// Whether the current player already took a card back, which is
+        /// allowed once per turn so undoing cannot be used to scan the board.
+        #[serde(default)]
+        undo_used: bool,
+        /// Sent by `release_unflips` once their delay is over, or by
+        /// `flush_unflips` as soon as the turn moves on.
+        #[serde(skip)]
+        held_unflips: Vec<HeldUnflips>,
     }
 
     impl Memory {
+        /// A game with the default settings, see `GameConfig`.
         pub fn new(id: String) -> Self {
-            let columns = 9;
-            let rows = 6;
-            let mut cards = Vec::with_capacity(columns * rows);
-            let mut rng = thread_rng();
-
-            let mut img = 0;
-            for i in 0..columns * rows {
-                cards.push(Card::new(LINKS[img].to_owned()));
-                if i % 2 != 0 {
-                    img += 1;
+            Self::with_config(id, GameConfig::default())
+                .expect("the default board fits the built-in images")
+        }
+
+        /// Fails with `InvalidBoardSize` for boards that cannot be split
+        /// into sets of `match_size`, with `NotEnoughImages` when there
+        /// are fewer distinct images than sets, and with `InvalidInput` when
+        /// `max_players` is below `MIN_PLAYERS`.
+        pub fn with_config(id: String, config: GameConfig) -> Result<Self, Rejection> {
+            if config.max_players < MIN_PLAYERS {
+                return Err(warp::reject::custom(InvalidInput));
+            }
+            let (columns, rows, size) = match &config.layout {
+                Some(layout) if layout.is_empty() || layout.contains(&0) => {
+                    return Err(warp::reject::custom(InvalidBoardSize));
                 }
+                Some(layout) => (
+                    layout.iter().copied().max().unwrap_or(0),
+                    layout.len(),
+                    layout.iter().sum(),
+                ),
+                None => (config.columns, config.rows, config.columns * config.rows),
+            };
+            let match_size = config.match_size;
+            if size == 0 || match_size < 2 || !size.is_multiple_of(match_size) {
+                return Err(warp::reject::custom(InvalidBoardSize));
+            }
+
+            let mut images = config
+                .images
+                .unwrap_or_else(|| LINKS.map(String::from).to_vec());
+            let mut seen = HashSet::new();
+            images.retain(|img| seen.insert(img.clone()));
+            if size / match_size > images.len() {
+                return Err(warp::reject::custom(NotEnoughImages {
+                    needed: size / match_size,
+                    supplied: images.len(),
+                }));
+            }
+
+            let mut cards = Vec::with_capacity(size);
+            let mut rng = match config.seed {
+                Some(seed) => StdRng::seed_from_u64(seed),
+                None => StdRng::from_entropy(),
+            };
+
+            for i in 0..size {
+                cards.push(Card::new(images[i / match_size].clone()));
             }
 
             cards.shuffle(&mut rng);
 
-            Memory {
+            Ok(Memory {
                 id,
                 players: HashMap::new(),
                 state: GameState::Lobby,
                 cards,
+                images,
+                columns,
+                rows,
+                layout: config.layout,
+                size,
+                match_size,
+                max_players: config.max_players,
+                admin_token: Self::generate_token(),
+                streak_bonus: config.streak_bonus,
+                mismatch_penalty: config.mismatch_penalty,
+                practice: config.practice,
+                target_matches: config.target_matches.filter(|&target| target > 0),
+                peeks: config.peeks,
+                last_flip: None,
+                last_scorer: None,
+                winners: Vec::new(),
+                started_at: None,
+                duration_secs: 0,
+                history: VecDeque::new(),
+                event_log: VecDeque::new(),
+                event_seq: 0,
+                spectators: HashMap::new(),
+                last_activity: Instant::now(),
+                reveal_ms: config.reveal_ms,
+                turn_mode: config.turn_mode,
+                scoring: config.scoring,
+                first_flip_at: None,
+                lobby_timer: None,
+                rng,
+                turn_order: Vec::new(),
                 current_turn: 0,
+                turn_number: 0,
+                mismatch: false,
+                undo_used: false,
+                held_unflips: Vec::new(),
+            })
+        }
+
+        /// Puts a finished game back into the lobby with a freshly shuffled
+        /// board, keeping its players but clearing their points.
+        pub fn reset(&mut self) {
+            for card in self.cards.iter_mut() {
+                card.flipped = false;
+                card.gone = false;
+            }
+            self.cards.shuffle(&mut self.rng);
+
+            for player in self.players.values_mut() {
+                player.points = 0;
+                player.ready = false;
+                player.turn = false;
+                player.current_streak = 0;
+                player.matches = 0;
+                player.peeks_left = self.peeks;
             }
+
+            self.state = GameState::Lobby;
+            self.current_turn = 0;
+            self.turn_number += 1;
+            self.mismatch = false;
+            self.undo_used = false;
+            self.held_unflips.clear();
+            self.last_scorer = None;
+            self.winners.clear();
+            self.started_at = None;
+            self.duration_secs = 0;
+            self.history.clear();
+            info!(game = %self.id, "Game restarted");
         }
 
         pub async fn start(&mut self) {
             self.state = GameState::Running;
-            let player = self.players.values_mut().nth(self.current_turn).unwrap();
+            self.undo_used = false;
+            self.started_at = Some(unix_timestamp());
+            self.current_turn = self.rng.gen_range(0..self.turn_order.len());
+            let player = self.current_player_mut();
             player.turn = true;
-            println!("Started game.");
+            let name = player.name.clone();
+            self.turn_number += 1;
+            info!(game = %self.id, player = %name, "Game started");
+            self.send_turn_response(name).await;
         }
 
         pub fn add_new_player(
             &mut self,
             name: String,
-        ) -> Result<String, crate::reject::AlreadyExists> {
-            if self.players.contains_key(&name) {
-                return Err(crate::reject::AlreadyExists);
+            color: Option<&str>,
+        ) -> Result<String, crate::reject::NameTaken> {
+            if self.players.values().any(|p| p.name == name) {
+                return Err(crate::reject::NameTaken);
+            }
+
+            let token = Self::generate_token();
+            let color = self.assign_color(color);
+
+            let mut player = Player::new(name.clone(), color);
+            player.peeks_left = self.peeks;
+            self.players.insert(token.clone(), player);
+            metrics::increment(&metrics::PLAYERS);
+            self.turn_order.push(token.clone());
+
+            info!(game = %self.id, player = %name, "Player joined");
+            Ok(token)
+        }
+
+        /// The preferred color if it is in the palette and free, otherwise the
+        /// first free one. Once every color is taken they are reused in order.
+        fn assign_color(&self, preferred: Option<&str>) -> String {
+            let taken = |color: &str| self.players.values().any(|p| p.color == color);
+            preferred
+                .and_then(|color| PALETTE.iter().find(|c| c.eq_ignore_ascii_case(color)))
+                .filter(|color| !taken(color))
+                .or_else(|| PALETTE.iter().find(|color| !taken(color)))
+                .unwrap_or(&PALETTE[self.players.len() % PALETTE.len()])
+                .to_string()
+        }
+
+        /// Issues a fresh token for a disconnected player with the given name,
+        /// keeping their points and turn. Players that still have an open
+        /// stream can't be taken over this way.
+        pub fn reconnect_player(&mut self, name: &str) -> Option<String> {
+            let old_token = self
+                .players
+                .iter()
+                .find(|(_, p)| p.name == name && !p.is_connected())
+                .map(|(token, _)| token.clone())?;
+
+            let player = self.players.remove(&old_token).unwrap();
+            let token = Self::generate_token();
+            self.players.insert(token.clone(), player);
+            for slot in self.turn_order.iter_mut().filter(|t| **t == old_token) {
+                *slot = token.clone();
             }
 
-            let token: String = thread_rng()
+            info!(game = %self.id, player = %name, "Player reconnected");
+            Some(token)
+        }
+
+        /// Replaces the admin token, so the old one stops working, and
+        /// returns the new one.
+        pub fn rotate_admin_token(&mut self) -> String {
+            self.admin_token = Self::generate_token();
+            self.admin_token.clone()
+        }
+
+        fn generate_token() -> String {
+            thread_rng()
                 .sample_iter(&rand::distributions::Alphanumeric)
                 .take(30)
                 .map(char::from)
-                .collect();
+                .collect()
+        }
 
-            self.players
-                .insert(token.clone(), Player::new(name.clone()));
+        pub async fn remove_player(&mut self, token: &str) -> Option<Player> {
+            let player = self.players.remove(token)?;
+            metrics::sub(&metrics::PLAYERS, 1);
+            self.held_unflips
+                .retain(|held| !player.senders.iter().any(|s| s.same_channel(&held.sender)));
+            let index = self.turn_order.iter().position(|t| t == token).unwrap();
+            self.turn_order.remove(index);
+            info!(game = %self.id, player = %player.name, "Player left");
+
+            if self.players.is_empty() {
+                if let GameState::Starting | GameState::Running | GameState::Paused = self.state {
+                    // Nobody is left to finish it, so spectators see a fresh board.
+                    self.reset();
+                    self.broadcast("state", self.get_state(None)).await;
+                }
+                self.current_turn = 0;
+                for card in self.cards.iter_mut() {
+                    card.flipped = false;
+                }
+                return Some(player);
+            }
 
-            println!("{} joined and got the token: {}", name, token);
-            Ok(token)
+            let len = self.turn_order.len();
+            match self.state {
+                GameState::Running | GameState::Paused if player.turn => {
+                    self.current_turn = match self.turn_mode {
+                        TurnMode::Reverse => (index + len - 1) % len,
+                        _ => index % len,
+                    };
+                    self.flush_unflips().await;
+                    let next = self.current_player_mut();
+                    next.turn = true;
+                    let name = next.name.clone();
+                    self.turn_number += 1;
+                    self.undo_used = false;
+                    for card_id in 0..self.cards.len() {
+                        if self.cards[card_id].flipped {
+                            self.cards[card_id].flipped = false;
+                            self.broadcast("unflipCard", UnflipResponse { card_id })
+                                .await;
+                        }
+                    }
+                    self.send_turn_response(name).await;
+                }
+                // Step back onto the previous player, so the next `next_turn`
+                // hands the turn to whoever followed the removed one.
+                GameState::Running | GameState::Paused if index == self.current_turn => {
+                    self.current_turn = match self.turn_mode {
+                        TurnMode::Reverse => index % len,
+                        _ => (index + len - 1) % len,
+                    };
+                }
+                _ if index < self.current_turn => self.current_turn -= 1,
+                _ => (),
+            }
+
+            Some(player)
         }
 
         pub async fn pick_card(
@@ -331,69 +1766,266 @@ pub mod memory {
             card_id: usize,
             token: String,
         ) -> Result<Json, Rejection> {
-            let other_card_img_path = {
-                let other_card = self.cards.iter().find(|x| x.flipped);
-                if let Some(card) = other_card {
-                    Some(card.img_path.clone())
-                } else {
-                    None
-                }
-            };
+            if self.mismatch {
+                return Err(warp::reject::custom(ResolvingMatch));
+            }
+            let flipped: Vec<String> = self
+                .cards
+                .iter()
+                .filter(|x| x.flipped)
+                .map(|card| card.img_path.clone())
+                .collect();
+            if flipped.len() >= self.match_size {
+                return Err(warp::reject::custom(TooManyFlipped));
+            }
 
             let (mut next, mut pair) = (false, false);
 
             let reply = if let Some(card) = self.cards.get_mut(card_id) {
-                if card.flipped || card.gone {
+                if card.gone {
+                    return Err(warp::reject::custom(InvalidCard));
+                }
+                if card.flipped {
                     return Err(warp::reject::custom(AlreadyFlipped));
                 }
                 card.flipped = true;
+                self.last_flip = Some(card_id);
+                if flipped.is_empty() {
+                    self.first_flip_at = Some(Instant::now());
+                }
+                let elapsed = self.first_flip_at.map_or(Duration::ZERO, |at| at.elapsed());
+                let img_path = card.img_path.clone();
                 let player = self.players.get_mut(&token).unwrap();
-                println!("{} picked {}", player.name, card_id);
-
-                (next, pair) =
-                    Self::check_for_pair(player, card.img_path.clone(), other_card_img_path);
-
-                let players = self.players.values().collect();
-                Self::send_flip_response(players, card.img_path.clone(), card_id).await;
+                info!(game = %self.id, player = %player.name, card = card_id, "Card picked");
+
+                (next, pair) = Self::check_for_match(
+                    player,
+                    &img_path,
+                    &flipped,
+                    self.match_size,
+                    self.scoring
+                        .points(player.current_streak, self.streak_bonus, elapsed),
+                    self.mismatch_penalty,
+                );
+
+                if self.history.len() == MAX_HISTORY {
+                    self.history.pop_front();
+                }
+                let name = player.name.clone();
+                self.history.push_back(MoveRecord {
+                    player_name: name.clone(),
+                    card_id,
+                    matched: pair,
+                    timestamp: unix_timestamp(),
+                });
+
+                self.send_flip_response(img_path, card_id, name).await;
+                metrics::increment(&metrics::PICKS);
                 Ok(warp::reply::json(&"Success"))
             } else {
                 Err(warp::reject::custom(InvalidCard))
             };
 
             if pair {
+                metrics::increment(&metrics::MATCHES);
+                self.flush_unflips().await;
+                self.turn_number += 1;
+                let mut matched = Vec::new();
                 for (i, card) in self.cards.iter_mut().enumerate() {
-                    if pair && card.flipped {
+                    if card.flipped {
                         card.gone = true;
                         card.flipped = false;
-                        Self::send_hide_response(self.players.values().collect(), i).await;
+                        matched.push(i);
                     }
                 }
-                if self.cards.iter().all(|x| x.gone) {
-                    self.state = GameState::Finished;
-                    broadcast_sse(
-                        "gameOver",
-                        GameOverResponse {
-                            game_state: self.state,
-                        },
-                        self.players.values().collect(),
-                    )
-                    .await;
+                let player = &self.players[&token];
+                let (name, points) = (player.name.clone(), player.points);
+                self.last_scorer = Some(name.clone());
+                let target_reached = self
+                    .target_matches
+                    .is_some_and(|target| player.matches >= target);
+                for i in matched {
+                    self.send_hide_response(i, name.clone(), points).await;
+                }
+                if target_reached {
+                    info!(game = %self.id, player = %name, "Target matches reached");
+                    self.finish(vec![name]).await;
+                } else if self.cards.iter().all(|x| x.gone) {
+                    let winners = self.winners();
+                    self.finish(winners).await;
                 }
             }
             if next {
-                self.next_turn();
+                self.mismatch = true;
             }
 
             reply
         }
 
-        pub fn get_state(&self, ready: bool) -> InitResponse {
+        /// Ends the game, announcing the winners and then the whole board,
+        /// so clients that missed a `hideCard` still show the right one.
+        async fn finish(&mut self, winners: Vec<String>) {
+            if let GameState::Finished = self.state {
+                return;
+            }
+            self.state = GameState::Finished;
+            self.winners = winners.clone();
+            self.duration_secs = self
+                .started_at
+                .map_or(0, |started| unix_timestamp().saturating_sub(started));
+            let res = GameOverResponse {
+                game_state: self.state,
+                winners,
+                leaderboard: self.standings(),
+                duration_secs: self.duration_secs,
+            };
+            self.broadcast("gameOver", res).await;
+
+            let layout = self.layout.clone();
+            let res = BoardResponse {
+                columns: self.columns,
+                rows: self.rows,
+                layout: &layout,
+                cards: self.cards.iter().map(CardResponse::from).collect(),
+            };
+            self.broadcast("finalBoard", res).await;
+        }
+
+        /// Uses up one of the player's peeks and returns every card's face
+        /// in board order.
+        pub fn peek(&mut self, token: &str) -> Result<Vec<CardFace>, Rejection> {
+            if self.peeks == 0 {
+                return Err(warp::reject::custom(PeeksDisabled));
+            }
+            let player = self
+                .players
+                .get_mut(token)
+                .ok_or_else(|| warp::reject::custom(InvalidToken))?;
+            if player.peeks_left == 0 {
+                return Err(warp::reject::custom(NoPeeksLeft));
+            }
+            player.peeks_left -= 1;
+            info!(game = %self.id, player = %player.name, left = player.peeks_left, "Player peeked");
+
+            Ok(self
+                .cards
+                .iter()
+                .map(|card| CardFace::new(&card.img_path))
+                .collect())
+        }
+
+        /// Marks a player as ready and tells whether that made every player
+        /// ready. Readying twice changes nothing and returns `false`, so of
+        /// several calls only the last new ready can start the game.
+        pub fn mark_ready(&mut self, token: &str) -> Result<bool, Rejection> {
+            if !matches!(self.state, GameState::Lobby) {
+                return Err(warp::reject::custom(AlreadyRunning));
+            }
+            let player = self
+                .players
+                .get_mut(token)
+                .ok_or_else(|| warp::reject::custom(InvalidToken))?;
+            if player.ready {
+                return Ok(false);
+            }
+            player.ready = true;
+            info!(game = %self.id, player = %player.name, "Player is ready");
+
+            Ok(self.players.values().all(|p| p.ready))
+        }
+
+        /// Locks the lobby while the countdown runs. The returned turn number
+        /// lets the countdown notice a restart in the meantime.
+        pub fn begin_countdown(&mut self) -> usize {
+            self.state = GameState::Starting;
+            info!(game = %self.id, "Countdown started");
+            self.turn_number
+        }
+
+        /// Arms a new lobby timer, disarming any earlier one, and returns the
+        /// moment that identifies it.
+        pub fn arm_lobby_timer(&mut self) -> Instant {
+            let armed = Instant::now();
+            self.lobby_timer = Some(armed);
+            armed
+        }
+
+        /// Whether the lobby timer armed at `armed` is still the current one.
+        pub fn is_lobby_timer(&self, armed: Instant) -> bool {
+            self.lobby_timer == Some(armed)
+        }
+
+        /// Marks every player ready, for lobbies that waited long enough.
+        pub fn ready_everyone(&mut self) {
+            for player in self.players.values_mut() {
+                player.ready = true;
+            }
+            info!(game = %self.id, "Lobby timed out, everyone is ready");
+        }
+
+        /// Reopens the lobby after a countdown that can no longer finish.
+        pub fn abort_countdown(&mut self) {
+            self.state = GameState::Lobby;
+            info!(game = %self.id, "Countdown aborted");
+        }
+
+        pub fn pause(&mut self) {
+            self.state = GameState::Paused;
+            info!(game = %self.id, "Game paused");
+        }
+
+        /// Continues a paused game. The turn number moves on so that timers
+        /// from before the pause are ignored.
+        pub fn resume(&mut self) {
+            self.state = GameState::Running;
+            self.turn_number += 1;
+            info!(game = %self.id, "Game resumed");
+        }
+
+        /// Everyone sharing the highest score, so ties yield several winners.
+        pub fn winners(&self) -> Vec<String> {
+            let Some(best) = self.players.values().map(|p| p.points).max() else {
+                return Vec::new();
+            };
+            let mut winners: Vec<String> = self
+                .players
+                .values()
+                .filter(|p| p.points == best)
+                .map(|p| p.name.clone())
+                .collect();
+            winners.sort();
+            winners
+        }
+
+        /// The players ordered by points, highest first, then by name.
+        pub fn standings(&self) -> Players {
+            let mut players: Players = self
+                .players
+                .values()
+                .map(|p| {
+                    (
+                        p.name.clone(),
+                        p.points,
+                        p.ready,
+                        p.turn,
+                        p.current_streak,
+                        p.color.clone(),
+                    )
+                })
+                .collect();
+            sort_players(&mut players);
+            players
+        }
+
+        /// The full game state, including the standing of the player holding
+        /// `token`, if any.
+        pub fn get_state(&self, token: Option<&str>) -> InitResponse {
             let flipped = self
                 .cards
                 .iter()
                 .enumerate()
                 .filter(|(_, x)| x.flipped)
-                .map(|(i, c)| (i, c.img_path.clone()))
+                .map(|(i, c)| (i, CardFace::new(&c.img_path)))
                 .collect::<Vec<_>>();
             let hidden = self
                 .cards
@@ -403,58 +2035,653 @@ pub mod memory {
                 .map(|(i, _)| i)
                 .collect::<Vec<_>>();
 
-            let players = self
+            let players = self.standings();
+            let player = token.and_then(|token| self.players.get(token));
+
+            InitResponse::from(self, player, flipped, hidden, players)
+        }
+
+        /// One more than the number of players with strictly more points, so
+        /// tied players share a rank.
+        pub fn rank(&self, points: usize) -> usize {
+            1 + self.players.values().filter(|p| p.points > points).count()
+        }
+
+        /// Counts every turn started, including the extra turn after a match,
+        /// so a timer started for one turn can tell whether it is still running.
+        pub fn turn_number(&self) -> usize {
+            self.turn_number
+        }
+
+        /// Whether there are enough players to start: one for a practice
+        /// game, otherwise `MIN_PLAYERS`.
+        pub fn has_enough_players(&self) -> bool {
+            let min = if self.practice { 1 } else { MIN_PLAYERS };
+            self.players.len() >= min
+        }
+
+        /// Turns the latest pick face down again, as long as the set it
+        /// belongs to is not complete. With pairs that is the first card.
+        /// Each turn allows one undo.
+        pub async fn unflip_last(&mut self) -> Result<usize, Rejection> {
+            let flipped = self.cards.iter().filter(|card| card.flipped).count();
+            let card_id = self
+                .last_flip
+                .filter(|&id| self.cards[id].flipped)
+                .filter(|_| flipped < self.match_size)
+                .ok_or_else(|| warp::reject::custom(NothingToUndo))?;
+            if self.undo_used {
+                return Err(warp::reject::custom(UndoUsed));
+            }
+
+            self.cards[card_id].flipped = false;
+            self.last_flip = None;
+            self.undo_used = true;
+            info!(game = %self.id, card = card_id, "Card unflipped");
+            self.broadcast("unflipCard", UnflipResponse { card_id })
+                .await;
+            Ok(card_id)
+        }
+
+        /// A mismatched pair stays face up until `skip_turn` hides it and
+        /// passes the turn on.
+        pub fn has_pending_mismatch(&self) -> bool {
+            self.mismatch
+        }
+
+        pub fn reveal_delay(&self) -> Duration {
+            Duration::from_millis(self.reveal_ms)
+        }
+
+        /// Takes the turn away once its time is up. Cards the player already
+        /// turned stay visible for the reveal delay, like a mismatch, and
+        /// the return value tells whether such a hide is pending.
+        pub fn expire_turn(&mut self) -> bool {
+            if !self.cards.iter().any(|card| card.flipped) {
+                return false;
+            }
+            self.current_player_mut().turn = false;
+            self.mismatch = true;
+            true
+        }
+
+        /// Turns the face up cards back and passes the turn on. The
+        /// `unflipCard` broadcasts are held back from players with a
+        /// `reveal_multiplier`, and the caller is to `release_unflips` them
+        /// after each of the returned delays.
+        pub async fn skip_turn(&mut self) -> Vec<Duration> {
+            self.flush_unflips().await;
+            self.mismatch = false;
+            self.undo_used = false;
+            let mut flipped = Vec::new();
+            for (i, card) in self.cards.iter_mut().enumerate() {
+                if card.flipped {
+                    card.flipped = false;
+                    flipped.push(i);
+                }
+            }
+            let reveal_delay = self.reveal_delay();
+            let lingering: Vec<(EventSender, Duration)> = self
                 .players
                 .values()
+                .filter(|p| p.reveal_multiplier > 1.0)
+                .flat_map(|p| {
+                    let delay = reveal_delay.mul_f64(p.reveal_multiplier - 1.0);
+                    p.senders.iter().map(move |sender| (sender.clone(), delay))
+                })
+                .collect();
+            let held: Vec<EventSender> = lingering.iter().map(|(s, _)| s.clone()).collect();
+            let mut events = Vec::new();
+            for i in flipped {
+                let res = UnflipResponse { card_id: i };
+                if let Some(event) = self.broadcast_except("unflipCard", res, &held).await {
+                    events.push((i, event));
+                }
+            }
+            for player in self.players.values_mut() {
+                player.turn = false;
+                player.current_streak = 0;
+            }
+            self.next_turn();
+            let name = self.current_player_mut().name.clone();
+            self.send_turn_response(name).await;
+
+            if events.is_empty() {
+                return Vec::new();
+            }
+            let mut delays = Vec::new();
+            for (sender, delay) in lingering {
+                if !delays.contains(&delay) {
+                    delays.push(delay);
+                }
+                self.held_unflips.push(HeldUnflips {
+                    sender,
+                    turn: self.turn_number,
+                    delay,
+                    events: events.clone(),
+                });
+            }
+            delays
+        }
+
+        /// Sends the `unflipCard` events `skip_turn` held back for `delay`
+        /// when it started turn `turn`. Once that turn is over they have
+        /// been flushed already, so a late call does nothing.
+        pub async fn release_unflips(&mut self, turn: usize, delay: Duration) {
+            if self.turn_number != turn {
+                return;
+            }
+            let (due, held) = std::mem::take(&mut self.held_unflips)
+                .into_iter()
+                .partition(|held| held.turn == turn && held.delay == delay);
+            self.held_unflips = held;
+            self.send_held_unflips(due).await;
+        }
+
+        /// Sends every held back `unflipCard` event right away, for when the
+        /// turn moves on before their delay is over.
+        pub async fn flush_unflips(&mut self) {
+            let held = std::mem::take(&mut self.held_unflips);
+            self.send_held_unflips(held).await;
+        }
+
+        /// Cards that were picked again in the meantime stay face up.
+        async fn send_held_unflips(&mut self, held: Vec<HeldUnflips>) {
+            for HeldUnflips { sender, events, .. } in held {
+                for (card_id, event) in events {
+                    if self.cards[card_id].flipped {
+                        continue;
+                    }
+                    if send_event(event, &sender).await.is_err() {
+                        self.drop_channel(&sender);
+                        break;
+                    }
+                }
+            }
+        }
+
+        pub fn touch(&mut self) {
+            self.last_activity = Instant::now();
+        }
+
+        /// Whether nobody is listening and nothing has happened for `ttl`.
+        pub fn is_idle(&self, ttl: Duration) -> bool {
+            self.listeners().is_empty() && self.last_activity.elapsed() >= ttl
+        }
+
+        /// Tokens of the players whose stream has been closed for at least
+        /// `grace`.
+        pub fn disconnected_players(&self, grace: Duration) -> Vec<String> {
+            self.players
+                .iter()
+                .filter(|(_, p)| p.disconnected_at.is_some_and(|at| at.elapsed() >= grace))
+                .map(|(token, _)| token.clone())
+                .collect()
+        }
+
+        /// Every open event stream of this game, players and spectators alike.
+        pub fn listeners(&self) -> Vec<&EventSender> {
+            self.players
+                .values()
+                .flat_map(|p| p.senders.iter())
+                .chain(self.spectators.values())
+                .collect()
+        }
+
+        /// Sends an event to every listener of this game and forgets the
+        /// channels that have been closed in the meantime.
+        /// Every broadcast is numbered and kept in the event log.
+        pub async fn broadcast(&mut self, event_name: &str, reply: impl serde::Serialize) {
+            self.broadcast_except(event_name, reply, &[]).await;
+        }
+
+        /// Like `broadcast`, but leaves out the streams in `except`. Returns
+        /// the event, so it can be sent to them later.
+        async fn broadcast_except(
+            &mut self,
+            event_name: &str,
+            reply: impl serde::Serialize,
+            except: &[EventSender],
+        ) -> Option<GameEvent> {
+            let Ok(mut event) = GameEvent::new(event_name, &reply) else {
+                return None;
+            };
+            self.event_seq += 1;
+            event.id = Some(self.event_seq);
+            if self.event_log.len() == MAX_EVENT_LOG {
+                self.event_log.pop_front();
+            }
+            self.event_log.push_back(event.clone());
+
+            let listeners = self
+                .listeners()
                 .into_iter()
-                .map(|p| (p.name.clone(), p.points, p.ready, p.turn))
+                .filter(|listener| !except.iter().any(|s| s.same_channel(listener)))
                 .collect();
+            let closed = broadcast_event(&event, listeners).await;
+            for sender in closed.iter() {
+                self.drop_channel(sender);
+            }
+            Some(event)
+        }
+
+        /// The broadcasts after the one numbered `last_id`, or `None` if some
+        /// of them are no longer in the log.
+        pub fn events_since(&self, last_id: u64) -> Option<Vec<GameEvent>> {
+            if last_id > self.event_seq {
+                return None;
+            }
+            let missed = (self.event_seq - last_id) as usize;
+            if missed > self.event_log.len() {
+                return None;
+            }
+            Some(
+                self.event_log
+                    .range(self.event_log.len() - missed..)
+                    .cloned()
+                    .collect(),
+            )
+        }
+
+        /// Forgets `sender`, whether a player or a spectator holds it.
+        pub fn drop_channel(&mut self, sender: &EventSender) {
+            for player in self.players.values_mut() {
+                if player.remove_sender(sender) {
+                    info!(game = %self.id, player = %player.name, "Dropped closed channel");
+                }
+            }
+            self.spectators.retain(|_, s| !s.same_channel(sender));
+            self.held_unflips
+                .retain(|held| !held.sender.same_channel(sender));
+        }
+
+        /// Registers a spectator stream and returns the token that lets the
+        /// spectator join as a player later on.
+        pub fn add_spectator(&mut self, sender: EventSender) -> String {
+            let token = Self::generate_token();
+            self.spectators.insert(token.clone(), sender);
+            token
+        }
+
+        pub fn has_spectator(&self, token: &str) -> bool {
+            self.spectators.contains_key(token)
+        }
+
+        /// Turns the spectator's stream into the stream of the player with
+        /// `player_token`.
+        pub fn promote_spectator(&mut self, spectator_token: &str, player_token: &str) {
+            let sender = self.spectators.remove(spectator_token);
+            if let (Some(player), Some(sender)) = (self.players.get_mut(player_token), sender) {
+                player.add_sender(sender);
+            }
+        }
+
+        /// Drops every open stream of this game, which ends them.
+        pub fn disconnect_all(&mut self) {
+            for player in self.players.values_mut() {
+                player.senders.clear();
+            }
+            self.spectators.clear();
+        }
+
+        /// Resolves a mismatch that was still on display when the game was
+        /// saved, since its hide timer did not survive the restart.
+        fn restore(&mut self) {
+            if self.admin_token.is_empty() {
+                self.admin_token = Self::generate_token();
+            }
+            if self.size == 0 {
+                self.size = self.cards.len();
+            }
+            // Saved before scoring profiles, when the bonus alone decided.
+            if self.streak_bonus > 0 && self.scoring == Scoring::Classic {
+                self.scoring = Scoring::Streak;
+            }
+            // Nobody is connected after a restart, so everyone gets the grace
+            // period to come back.
+            for player in self.players.values_mut() {
+                player.disconnected_at = Some(Instant::now());
+            }
+            // The countdown task did not survive the restart.
+            if let GameState::Starting = self.state {
+                self.state = GameState::Lobby;
+            }
+            if self.mismatch {
+                self.mismatch = false;
+                for card in self.cards.iter_mut() {
+                    card.flipped = false;
+                }
+                for player in self.players.values_mut() {
+                    player.turn = false;
+                }
+                self.next_turn();
+            }
+        }
+
+        /// The name of the player whose turn it is, while the game is running
+        /// or paused.
+        pub fn current_player(&self) -> Option<&str> {
+            match self.state {
+                GameState::Running | GameState::Paused => self
+                    .turn_order
+                    .get(self.current_turn)
+                    .and_then(|token| self.players.get(token))
+                    .map(|player| player.name.as_str()),
+                _ => None,
+            }
+        }
 
-            InitResponse::from(self.state, ready, flipped, hidden, players)
+        fn current_player_mut(&mut self) -> &mut Player {
+            let token = &self.turn_order[self.current_turn];
+            self.players.get_mut(token).unwrap()
         }
 
         fn next_turn(&mut self) {
-            self.current_turn = (self.current_turn + 1) % self.players.len();
-            let player = self.players.values_mut().nth(self.current_turn).unwrap();
-            player.turn = true;
+            let len = self.turn_order.len();
+            self.current_turn = match self.turn_mode {
+                TurnMode::Sequential => (self.current_turn + 1) % len,
+                TurnMode::Reverse => (self.current_turn + len - 1) % len,
+                TurnMode::Random if len > 1 => {
+                    (self.current_turn + self.rng.gen_range(1..len)) % len
+                }
+                TurnMode::Random => 0,
+            };
+            self.current_player_mut().turn = true;
+            self.turn_number += 1;
             for card in self.cards.iter_mut() {
                 card.flipped = false;
             }
-            println!("Next players turn.");
+            info!(game = %self.id, turn = self.turn_number, "Next player's turn");
         }
 
-        fn check_for_pair(
+        /// Compares a newly flipped card against the cards already face up.
+        /// Returns whether the turn is lost and whether a full match is showing.
+        /// `points` are awarded if it is.
+        fn check_for_match(
             player: &mut Player,
-            card: String,
-            other_card: Option<String>,
+            card: &str,
+            flipped: &[String],
+            match_size: usize,
+            points: usize,
+            mismatch_penalty: usize,
         ) -> (bool, bool) {
-            if let Some(other_card) = other_card {
-                if card == other_card {
-                    player.points += 1;
-                    return (false, true);
-                } else {
-                    player.turn = false;
-                    return (true, false);
-                }
+            if flipped.iter().any(|other| other != card) {
+                player.turn = false;
+                player.current_streak = 0;
+                player.points = player.points.saturating_sub(mismatch_penalty);
+                return (true, false);
+            }
+            if flipped.len() + 1 == match_size {
+                player.points += points;
+                player.current_streak += 1;
+                player.matches += 1;
+                return (false, true);
             }
             (false, false)
         }
 
-        async fn send_flip_response(players: Vec<&Player>, img_path: String, card_id: usize) {
-            let res = FlipResponse { img_path, card_id };
-            broadcast_sse("flipCard", res, players).await
+        async fn send_flip_response(&mut self, img_path: String, card_id: usize, name: String) {
+            let res = FlipResponse {
+                face: CardFace::new(&img_path),
+                card_id,
+                name,
+                reveal_ms: self.reveal_ms,
+            };
+            self.broadcast("flipCard", res).await
         }
 
-        async fn send_hide_response(players: Vec<&Player>, card_id: usize) {
-            let res = HideResponse { card_id };
-            broadcast_sse("hideCard", res, players).await
+        async fn send_hide_response(&mut self, card_id: usize, name: String, points: usize) {
+            let res = HideResponse {
+                card_id,
+                name,
+                points,
+            };
+            self.broadcast("hideCard", res).await
         }
+
+        async fn send_turn_response(&mut self, name: String) {
+            self.last_scorer = None;
+            let res = TurnResponse { name };
+            self.broadcast("turn", res).await
+        }
+    }
+
+    fn default_reveal_ms() -> u64 {
+        DEFAULT_REVEAL_MS
+    }
+
+    /// How many create and join requests one address may make: `burst` in a
+    /// row, with one more allowed every `interval`. A zero burst disables
+    /// the limit.
+    #[derive(Clone, Copy, Default, Debug)]
+    pub struct RateLimit {
+        pub burst: u32,
+        pub interval: Duration,
+    }
+
+    /// What is left of one address's `RateLimit`.
+    pub struct Bucket {
+        tokens: f64,
+        refilled: Instant,
     }
 
     #[derive(Default)]
     pub struct MemoryStore {
-        pub game: Option<Memory>,
+        pub games: HashMap<String, Memory>,
         pub master_key: String,
+        /// How long a player may hold the turn before it is skipped.
+        /// A zero duration disables the timeout.
+        pub turn_timeout: Duration,
+        /// The settings of games created without their own.
+        pub defaults: GameConfig,
+        /// The shortest time allowed between two picks of the same player.
+        pub pick_interval: Duration,
+        /// How long everyone sees the countdown before a game starts. A zero
+        /// duration starts games right away.
+        pub countdown: Duration,
+        /// How long a lobby waits after its first player joined before it
+        /// readies everyone and starts. A zero duration waits forever.
+        pub lobby_timeout: Duration,
+        /// How many unfinished games may exist at once. Finished games wait
+        /// for a restart or the idle reaper and do not count. Zero means no
+        /// limit.
+        pub max_games: usize,
+        /// Relaxes the cookie attributes so they work over plain HTTP.
+        pub dev_mode: bool,
+        /// How long a rotated master key must be, see `key_weakness`.
+        pub min_key_length: usize,
+        /// Lets the master key be rotated to a weak one, for local
+        /// development.
+        pub allow_weak_key: bool,
+        /// Sends a HEAD request to every custom deck image before creating
+        /// the game.
+        pub check_images: bool,
+        /// Where the games are written after every change, if anywhere.
+        pub save_path: Option<PathBuf>,
+        /// Operator dashboards listening on `GET /admin/stream`.
+        pub admin_senders: Vec<EventSender>,
+        pub rate_limit: RateLimit,
+        /// Token buckets of the addresses that made requests lately.
+        pub buckets: HashMap<IpAddr, Bucket>,
+    }
+
+    impl MemoryStore {
+        pub fn get_game(&self, id: &str) -> Result<&Memory, Rejection> {
+            self.games
+                .get(id)
+                .ok_or_else(|| warp::reject::custom(NoGameExists))
+        }
+
+        pub fn get_game_mut(&mut self, id: &str) -> Result<&mut Memory, Rejection> {
+            self.games
+                .get_mut(id)
+                .ok_or_else(|| warp::reject::custom(NoGameExists))
+        }
+
+        pub fn insert_game(&mut self, game: Memory) {
+            metrics::increment(&metrics::GAMES_CREATED);
+            metrics::increment(&metrics::ACTIVE_GAMES);
+            self.games.insert(game.id.clone(), game);
+        }
+
+        /// Takes a token from the bucket of `ip`, refilled up to `now`.
+        /// Returns whether there was one.
+        pub fn take_token(&mut self, ip: IpAddr, now: Instant) -> bool {
+            let RateLimit { burst, interval } = self.rate_limit;
+            if burst == 0 {
+                return true;
+            }
+            let refill = |bucket: &Bucket| {
+                let elapsed = now.saturating_duration_since(bucket.refilled);
+                (bucket.tokens + elapsed.as_secs_f64() / interval.as_secs_f64()).min(burst as f64)
+            };
+            if self.buckets.len() >= MAX_RATE_LIMITED_ADDRS {
+                self.buckets
+                    .retain(|_, bucket| refill(bucket) < burst as f64);
+            }
+
+            let bucket = self.buckets.entry(ip).or_insert(Bucket {
+                tokens: burst as f64,
+                refilled: now,
+            });
+            bucket.tokens = refill(bucket);
+            bucket.refilled = now;
+            if bucket.tokens < 1.0 {
+                return false;
+            }
+            bucket.tokens -= 1.0;
+            true
+        }
+
+        pub fn remove_game(&mut self, id: &str) -> Option<Memory> {
+            let game = self.games.remove(id)?;
+            metrics::sub(&metrics::ACTIVE_GAMES, 1);
+            metrics::sub(&metrics::PLAYERS, game.players.len());
+            Some(game)
+        }
+
+        /// Sends an event to every admin stream and forgets the ones that
+        /// have been closed.
+        pub async fn notify_admins(&mut self, event_name: &str, reply: impl serde::Serialize) {
+            let closed =
+                broadcast_sse(event_name, reply, self.admin_senders.iter().collect()).await;
+            for sender in closed.iter() {
+                self.drop_admin(sender);
+            }
+        }
+
+        pub fn drop_admin(&mut self, sender: &EventSender) {
+            self.admin_senders.retain(|s| !s.same_channel(sender));
+        }
+
+        pub fn save(&self) {
+            let Some(path) = &self.save_path else {
+                return;
+            };
+
+            match serde_json::to_string(&self.games) {
+                Ok(json) => {
+                    if let Err(err) = fs::write(path, json) {
+                        error!(path = %path.display(), error = %err, "Failed to save games");
+                    }
+                }
+                Err(err) => error!(error = %err, "Failed to serialize games"),
+            }
+        }
+
+        /// Reads the games saved at `path`. A missing file yields no games.
+        pub fn load(path: &Path) -> std::io::Result<HashMap<String, Memory>> {
+            let json = match fs::read_to_string(path) {
+                Ok(json) => json,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(HashMap::new()),
+                Err(err) => return Err(err),
+            };
+
+            let mut games: HashMap<String, Memory> = serde_json::from_str(&json)?;
+            for game in games.values_mut() {
+                game.restore();
+                metrics::add(&metrics::PLAYERS, game.players.len());
+            }
+            metrics::add(&metrics::ACTIVE_GAMES, games.len());
+            info!(count = games.len(), path = %path.display(), "Restored games");
+            Ok(games)
+        }
+    }
+}
+
+/// Process-wide counters and gauges, kept in atomics so that scraping never
+/// waits on the game store.
+pub mod metrics {
+    use std::fmt::Write;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    pub static GAMES_CREATED: AtomicUsize = AtomicUsize::new(0);
+    pub static ACTIVE_GAMES: AtomicUsize = AtomicUsize::new(0);
+    pub static PLAYERS: AtomicUsize = AtomicUsize::new(0);
+    pub static PICKS: AtomicUsize = AtomicUsize::new(0);
+    pub static MATCHES: AtomicUsize = AtomicUsize::new(0);
+    pub static BROADCASTS: AtomicUsize = AtomicUsize::new(0);
+
+    const METRICS: [(&str, &str, &str, &AtomicUsize); 6] = [
+        (
+            "memory_games_created_total",
+            "counter",
+            "Games created since the server started.",
+            &GAMES_CREATED,
+        ),
+        (
+            "memory_active_games",
+            "gauge",
+            "Games currently held in memory.",
+            &ACTIVE_GAMES,
+        ),
+        (
+            "memory_players",
+            "gauge",
+            "Players currently in any game.",
+            &PLAYERS,
+        ),
+        (
+            "memory_picks_total",
+            "counter",
+            "Cards picked successfully.",
+            &PICKS,
+        ),
+        (
+            "memory_matches_total",
+            "counter",
+            "Matches found.",
+            &MATCHES,
+        ),
+        (
+            "memory_broadcasts_total",
+            "counter",
+            "Events broadcast to the listeners of a game.",
+            &BROADCASTS,
+        ),
+    ];
+
+    pub fn increment(metric: &AtomicUsize) {
+        metric.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add(metric: &AtomicUsize, n: usize) {
+        metric.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn sub(metric: &AtomicUsize, n: usize) {
+        metric.fetch_sub(n, Ordering::Relaxed);
+    }
+
+    /// Renders every metric in the Prometheus text exposition format.
+    pub fn render() -> String {
+        let mut out = String::new();
+        for (name, kind, help, value) in METRICS {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            let _ = writeln!(out, "{} {}", name, value.load(Ordering::Relaxed));
+        }
+        out
     }
 }
 
@@ -489,3 +2716,138 @@ pub mod icons {
         "https://cdn.pixabay.com/photo/2022/07/09/22/16/michael-jordan-7311821_960_720.png",
     ];
 }
+
+/// Checks on the image URLs of a custom deck, before a game is built from it.
+pub mod deck {
+    use std::time::Duration;
+
+    use futures_util::future::join_all;
+    use warp::http::{header, Method, Request, Uri};
+    use warp::hyper::{Body, Client};
+
+    pub const MAX_IMAGE_URL_LENGTH: usize = 2048;
+    pub const IMAGE_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+    const MAX_REDIRECTS: usize = 3;
+
+    /// The URLs that are too long or not plain http(s) links.
+    pub fn malformed_urls(images: &[String]) -> Vec<String> {
+        images
+            .iter()
+            .filter(|url| url.len() > MAX_IMAGE_URL_LENGTH || !is_web_url(url))
+            .cloned()
+            .collect()
+    }
+
+    /// Where `image_route` serves the files in `images/`.
+    pub const LOCAL_IMAGE_PREFIX: &str = "/img/";
+
+    /// A card image the way clients should load it.
+    #[derive(serde::Serialize, Clone, PartialEq, Debug)]
+    pub struct CardFace {
+        /// A web URL, or a path under `LOCAL_IMAGE_PREFIX` on this server.
+        pub img_path: String,
+        /// Whether `img_path` is on another host, for clients that load
+        /// those through a proxy.
+        pub is_external: bool,
+    }
+
+    impl CardFace {
+        /// Passes web URLs through and refers to anything else as a file
+        /// under `LOCAL_IMAGE_PREFIX`, however the stored path names it.
+        pub fn new(img_path: &str) -> Self {
+            if is_web_url(img_path) {
+                return CardFace {
+                    img_path: img_path.to_owned(),
+                    is_external: true,
+                };
+            }
+            let name = img_path.trim_start_matches("./").trim_start_matches('/');
+            let name = name
+                .strip_prefix("img/")
+                .or_else(|| name.strip_prefix("images/"))
+                .unwrap_or(name);
+            CardFace {
+                img_path: format!("{}{}", LOCAL_IMAGE_PREFIX, name),
+                is_external: false,
+            }
+        }
+    }
+
+    fn is_web_url(url: &str) -> bool {
+        let Ok(uri) = url.parse::<Uri>() else {
+            return false;
+        };
+        matches!(uri.scheme_str(), Some("http" | "https")) && uri.host().is_some()
+    }
+
+    /// The URLs that do not answer a HEAD request with an image, following a
+    /// few redirects. Every URL is checked at once.
+    pub async fn unreachable_urls(images: &[String]) -> Vec<String> {
+        let https = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_webpki_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
+        let client = Client::builder().build::<_, Body>(https);
+
+        let checks = images.iter().map(|url| {
+            let client = client.clone();
+            async move {
+                let reachable = tokio::time::timeout(IMAGE_CHECK_TIMEOUT, is_image(&client, url))
+                    .await
+                    .unwrap_or(false);
+                (!reachable).then(|| url.clone())
+            }
+        });
+        join_all(checks).await.into_iter().flatten().collect()
+    }
+
+    async fn is_image<C>(client: &Client<C>, url: &str) -> bool
+    where
+        C: warp::hyper::client::connect::Connect + Clone + Send + Sync + 'static,
+    {
+        let mut uri = match url.parse::<Uri>() {
+            Ok(uri) => uri,
+            Err(_) => return false,
+        };
+        for _ in 0..=MAX_REDIRECTS {
+            let Ok(request) = Request::builder()
+                .method(Method::HEAD)
+                .uri(uri.clone())
+                .body(Body::empty())
+            else {
+                return false;
+            };
+            let Ok(response) = client.request(request).await else {
+                return false;
+            };
+            if response.status().is_redirection() {
+                let location = response
+                    .headers()
+                    .get(header::LOCATION)
+                    .and_then(|location| location.to_str().ok())
+                    .and_then(|location| location.parse::<Uri>().ok());
+                match location {
+                    Some(next) if next.scheme().is_some() => uri = next,
+                    Some(next) => {
+                        let mut parts = uri.into_parts();
+                        parts.path_and_query = next.path_and_query().cloned();
+                        let Ok(next) = Uri::from_parts(parts) else {
+                            return false;
+                        };
+                        uri = next;
+                    }
+                    None => return false,
+                }
+                continue;
+            }
+            return response.status().is_success()
+                && response
+                    .headers()
+                    .get(header::CONTENT_TYPE)
+                    .and_then(|content_type| content_type.to_str().ok())
+                    .is_some_and(|content_type| content_type.starts_with("image/"));
+        }
+        false
+    }
+}
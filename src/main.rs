@@ -1,109 +1,202 @@
 use std::env;
-
-use memory_backend::memory::{MemoryStore, Store};
-use memory_backend::queries::{CreateQuery, JoinQuery, PickQuery};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use memory_backend::handler::{
+    schedule_turn_timeout, shutdown, spawn_disconnect_reaper, spawn_idle_reaper,
+};
+use memory_backend::memory::{
+    GameConfig, GameState, MemoryStore, RateLimit, Store, DEFAULT_MAX_PLAYERS, DEFAULT_REVEAL_MS,
+    MIN_PLAYERS,
+};
+use memory_backend::queries::key_weakness;
 use memory_backend::reject::handle_rejection;
+use memory_backend::routes::api;
 use tokio::sync::RwLock;
+use tracing_subscriber::EnvFilter;
 use warp::Filter;
 
-use crate::handler::*;
-
-mod handler;
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
 
 #[tokio::main]
 async fn main() {
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .init();
+
     let key = env::var("MASTER_KEY").expect("No MASTER_KEY set");
+    let min_key_length: String = env::var("MIN_KEY_LENGTH").unwrap_or("16".to_owned());
+    let min_key_length = min_key_length
+        .parse::<usize>()
+        .expect("MIN_KEY_LENGTH is not a valid number");
+    let allow_weak_key = env::var("ALLOW_WEAK_KEY").is_ok_and(|v| v == "1" || v == "true");
+    if let Some(weakness) = key_weakness(&key, min_key_length) {
+        if !allow_weak_key {
+            panic!(
+                "MASTER_KEY {}; set ALLOW_WEAK_KEY=true for local development",
+                weakness
+            );
+        }
+        tracing::warn!(weakness, "Starting with a weak MASTER_KEY");
+    }
 
     let cors = warp::cors()
-        .allow_any_origin()
         .allow_credentials(true)
         .allow_headers(vec![
             "User-Agent",
             "Sec-Fetch-Mode",
             "Referer",
             "Origin",
-            "Access-Contro-Allow-Origin",
+            "Access-Control-Allow-Origin",
             "Access-Control-Request-Method",
             "Access-Control-Request-Headers",
             "Content-Type",
             "Authorization",
         ])
         .allow_methods(vec!["GET", "POST", "PUT", "DELETE", "OPTIONS"]);
+    let cors = match env::var("ALLOWED_ORIGINS") {
+        Ok(origins) => cors.allow_origins(
+            origins
+                .split(',')
+                .map(str::trim)
+                .filter(|origin| !origin.is_empty()),
+        ),
+        Err(_) => {
+            tracing::warn!("ALLOWED_ORIGINS not set, allowing any origin");
+            cors.allow_any_origin()
+        }
+    };
+
+    let turn_timeout: String = env::var("TURN_TIMEOUT").unwrap_or("30".to_owned());
+    let turn_timeout = turn_timeout
+        .parse::<u64>()
+        .expect("TURN_TIMEOUT is not a valid number of seconds");
+
+    let reveal_delay: String = env::var("REVEAL_DELAY").unwrap_or(DEFAULT_REVEAL_MS.to_string());
+    let reveal_delay = reveal_delay
+        .parse::<u64>()
+        .expect("REVEAL_DELAY is not a valid number of milliseconds");
+
+    let pick_interval: String = env::var("PICK_INTERVAL").unwrap_or("250".to_owned());
+    let pick_interval = pick_interval
+        .parse::<u64>()
+        .expect("PICK_INTERVAL is not a valid number of milliseconds");
+
+    let countdown: String = env::var("COUNTDOWN").unwrap_or("3".to_owned());
+    let countdown = countdown
+        .parse::<u64>()
+        .expect("COUNTDOWN is not a valid number of seconds");
+
+    let lobby_timeout: String = env::var("LOBBY_TIMEOUT").unwrap_or("0".to_owned());
+    let lobby_timeout = lobby_timeout
+        .parse::<u64>()
+        .expect("LOBBY_TIMEOUT is not a valid number of seconds");
+
+    let rate_limit_burst: String = env::var("RATE_LIMIT_BURST").unwrap_or("10".to_owned());
+    let rate_limit_burst = rate_limit_burst
+        .parse::<u32>()
+        .expect("RATE_LIMIT_BURST is not a valid number");
+
+    let rate_limit_interval: String = env::var("RATE_LIMIT_INTERVAL").unwrap_or("6".to_owned());
+    let rate_limit_interval = rate_limit_interval
+        .parse::<u64>()
+        .expect("RATE_LIMIT_INTERVAL is not a valid number of seconds");
+
+    let idle_ttl: String = env::var("IDLE_TTL").unwrap_or("3600".to_owned());
+    let idle_ttl = idle_ttl
+        .parse::<u64>()
+        .expect("IDLE_TTL is not a valid number of seconds");
+
+    let disconnect_grace: String = env::var("DISCONNECT_GRACE").unwrap_or("30".to_owned());
+    let disconnect_grace = disconnect_grace
+        .parse::<u64>()
+        .expect("DISCONNECT_GRACE is not a valid number of seconds");
+
+    let max_players = match env::var("MAX_PLAYERS") {
+        Ok(max_players) => max_players
+            .parse::<usize>()
+            .expect("MAX_PLAYERS is not a valid number"),
+        Err(_) => DEFAULT_MAX_PLAYERS,
+    };
+    assert!(
+        max_players >= MIN_PLAYERS,
+        "MAX_PLAYERS must be at least {}",
+        MIN_PLAYERS
+    );
+
+    let max_games: String = env::var("MAX_GAMES").unwrap_or("100".to_owned());
+    let max_games = max_games
+        .parse::<usize>()
+        .expect("MAX_GAMES is not a valid number");
+
+    let dev_mode = env::var("DEV").is_ok_and(|v| v == "1" || v == "true");
+    if dev_mode {
+        tracing::warn!("DEV is set, cookies are sent without Secure");
+    }
+
+    let check_images = env::var("CHECK_IMAGES").is_ok_and(|v| v == "1" || v == "true");
+
+    let save_path = env::var("SAVE_FILE").ok().map(PathBuf::from);
+    let games = match &save_path {
+        Some(path) => MemoryStore::load(path).expect("Failed to load SAVE_FILE"),
+        None => Default::default(),
+    };
+    let running: Vec<(String, usize)> = games
+        .values()
+        .filter(|game| matches!(game.state, GameState::Running))
+        .map(|game| (game.id.clone(), game.turn_number()))
+        .collect();
 
     let store = Store::new(RwLock::new(MemoryStore {
-        game: None,
+        games,
         master_key: key.clone(),
+        turn_timeout: Duration::from_secs(turn_timeout),
+        defaults: GameConfig::default()
+            .max_players(max_players)
+            .reveal_ms(reveal_delay),
+        pick_interval: Duration::from_millis(pick_interval),
+        countdown: Duration::from_secs(countdown),
+        lobby_timeout: Duration::from_secs(lobby_timeout),
+        max_games,
+        dev_mode,
+        min_key_length,
+        allow_weak_key,
+        check_images,
+        save_path,
+        admin_senders: Vec::new(),
+        rate_limit: RateLimit {
+            burst: rate_limit_burst,
+            interval: Duration::from_secs(rate_limit_interval),
+        },
+        buckets: Default::default(),
     }));
-    let store = warp::any().map(move || store.clone());
-
-    let ping_route = warp::get()
-        .and(warp::cookie::optional("memory_token"))
-        .and(warp::path("ping"))
-        .and(warp::path::end())
-        .and(store.clone())
-        .and_then(ping);
-
-    let key_route = warp::get()
-        .and(warp::path("key"))
-        .and(warp::query::raw())
-        .and(warp::path::end())
-        .and(store.clone())
-        .and_then(check_key);
-
-    let create_route = warp::post()
-        .and(warp::cookie("master_key"))
-        .and(warp::path("create"))
-        .and(warp::query::<CreateQuery>())
-        .and(warp::path::end())
-        .and(store.clone())
-        .and_then(create);
-
-    let delete_route = warp::post()
-        .and(warp::cookie("master_key"))
-        .and(warp::path("delete"))
-        .and(warp::path::end())
-        .and(store.clone())
-        .and_then(delete);
-
-    let join_route = warp::post()
-        .and(warp::path("join"))
-        .and(warp::query::<JoinQuery>())
-        .and(warp::path::end())
-        .and(store.clone())
-        .and_then(join);
-
-    let game_route = warp::get()
-        .and(warp::path("game"))
-        .and(warp::cookie("memory_token"))
-        .and(warp::path::end())
-        .and(store.clone())
-        .and_then(game_message);
-
-    let ready_route = warp::post()
-        .and(warp::cookie("memory_token"))
-        .and(warp::path("ready"))
-        .and(warp::path::end())
-        .and(store.clone())
-        .and_then(ready);
-
-    let pick_card_route = warp::post()
-        .and(warp::cookie("memory_token"))
-        .and(warp::path("pick_card"))
-        .and(warp::query::<PickQuery>())
-        .and(warp::path::end())
-        .and(store.clone())
-        .and_then(pick_card);
-
-    let image_route = warp::path("img").and(warp::fs::dir("images"));
-
-    let routes = ping_route
-        .or(key_route)
-        .or(create_route)
-        .or(delete_route)
-        .or(join_route)
-        .or(game_route)
-        .or(ready_route)
-        .or(pick_card_route)
+    for (id, turn) in running {
+        schedule_turn_timeout(store.clone(), id, turn, Duration::from_secs(turn_timeout));
+    }
+    spawn_idle_reaper(store.clone(), Duration::from_secs(idle_ttl));
+    spawn_disconnect_reaper(store.clone(), Duration::from_secs(disconnect_grace));
+    let shared_store = store.clone();
+
+    let image_max_age: String = env::var("IMAGE_MAX_AGE").unwrap_or("86400".to_owned());
+    let image_max_age = image_max_age
+        .parse::<u64>()
+        .expect("IMAGE_MAX_AGE is not a valid number of seconds");
+    let cache_control = format!("public, max-age={}", image_max_age);
+    let missing_image: String =
+        env::var("MISSING_IMAGE").unwrap_or("images/missing.svg".to_owned());
+
+    // Unknown images get a placeholder, so a broken img_path still shows a
+    // card face. The placeholder is not cached, in case the image appears.
+    let image_route = warp::path("img").and(
+        warp::fs::dir("images")
+            .map(move |file| warp::reply::with_header(file, "Cache-Control", cache_control.clone()))
+            .or(warp::fs::file(missing_image)
+                .map(|file| warp::reply::with_status(file, warp::http::StatusCode::NOT_FOUND))),
+    );
+
+    let routes = api(store)
         .or(image_route)
         .with(cors)
         .recover(handle_rejection);
@@ -111,6 +204,42 @@ async fn main() {
     let port: String = env::var("PORT").unwrap_or("8080".to_owned());
     let port = port.parse::<u16>().expect("PORT is not a valid number");
 
-    println!("Listening on port {port}");
-    warp::serve(routes).run(([0, 0, 0, 0], port)).await;
+    let (stop_tx, stop_rx) = tokio::sync::oneshot::channel::<()>();
+    let (_, server) =
+        warp::serve(routes).bind_with_graceful_shutdown(([0, 0, 0, 0], port), async {
+            stop_rx.await.ok();
+        });
+    let server = tokio::spawn(server);
+    tracing::info!(port, "Listening");
+
+    shutdown_signal().await;
+    tracing::info!("Shutting down");
+    shutdown(shared_store).await;
+    let _ = stop_tx.send(());
+    if tokio::time::timeout(SHUTDOWN_GRACE, server).await.is_err() {
+        tracing::warn!("Connections still open after grace period, exiting anyway");
+    }
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to listen for Ctrl-C");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => (),
+        _ = terminate => (),
+    }
 }
@@ -0,0 +1,501 @@
+use serde::de::DeserializeOwned;
+use warp::{Filter, Rejection, Reply};
+
+use crate::handler::*;
+use crate::memory::Store;
+use crate::queries::{
+    AnnounceQuery, ChatQuery, ClaimAdminBody, CreateBody, CreateQuery, GameQuery, GamesQuery,
+    JoinQuery, KickQuery, LeaderboardQuery, PickQuery, RotateKeyBody, TransferAdminQuery,
+};
+use crate::reject::{BodyTooLarge, InvalidGameAdmin};
+
+const MAX_IMAGES_BODY: u64 = 64 * 1024;
+const MAX_ACTION_BODY: u64 = 4 * 1024;
+
+/// Every route `api` serves, and the card images the server mounts next to
+/// it, listed in the body of a 404.
+pub const ENDPOINTS: [&str; 37] = [
+    "GET /health",
+    "GET /metrics",
+    "GET /games",
+    "GET /ping",
+    "GET /key",
+    "POST /rotate_key",
+    "POST /announce",
+    "GET /admin/stream",
+    "POST /create",
+    "POST /delete",
+    "POST /restart",
+    "POST /pause",
+    "POST /resume",
+    "GET /history",
+    "GET /board",
+    "GET /card/{index}",
+    "POST /join",
+    "POST /leave",
+    "POST /kick",
+    "POST /transfer_admin",
+    "POST /claim_admin",
+    "GET /game",
+    "GET /state",
+    "GET /turn",
+    "GET /config",
+    "GET /leaderboard",
+    "GET /watch",
+    "POST /promote",
+    "GET /ws",
+    "POST /ready",
+    "POST /unready",
+    "POST /pick_card",
+    "POST /skip",
+    "POST /peek",
+    "POST /unflip",
+    "POST /chat",
+    "GET /img/{file}",
+];
+
+/// Every API route on `store`. CORS, the image files and rejection handling
+/// are left to the caller. Each route matches its whole path first, so a
+/// request for an unknown path is rejected as not found rather than for a
+/// missing cookie or the wrong method.
+pub fn api(store: Store) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let store = warp::any().map(move || store.clone());
+    // Rejects requests from addresses that used up their `RateLimit`.
+    let rate_limited = warp::addr::remote()
+        .and(store.clone())
+        .and_then(rate_limit)
+        .untuple_one();
+
+    let health_route = warp::path("health")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(store.clone())
+        .and_then(health);
+
+    let games_route = warp::path("games")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::query::<GamesQuery>())
+        .and(store.clone())
+        .and_then(list_games);
+
+    let metrics_route = warp::path("metrics")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and_then(metrics);
+
+    let ping_route = warp::path("ping")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::cookie::optional("memory_token"))
+        .and(warp::query::<GameQuery>())
+        .and(store.clone())
+        .and_then(ping);
+
+    let key_route = warp::path("key")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::query::raw())
+        .and(store.clone())
+        .and_then(check_key);
+
+    let rotate_key_route = warp::path("rotate_key")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::cookie("master_key"))
+        .and(warp::body::content_length_limit(MAX_ACTION_BODY))
+        .and(warp::body::json::<RotateKeyBody>())
+        .and(store.clone())
+        .and_then(rotate_key);
+
+    let announce_route = warp::path("announce")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::cookie("master_key"))
+        .and(warp::query::<AnnounceQuery>())
+        .and(store.clone())
+        .and_then(announce);
+
+    let admin_stream_route = warp::path!("admin" / "stream")
+        .and(warp::get())
+        .and(warp::cookie("master_key"))
+        .and(store.clone())
+        .and_then(admin_stream);
+
+    let create_route = warp::path("create")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(rate_limited.clone())
+        .and(warp::cookie("master_key"))
+        .and(
+            warp::header::optional::<u64>("content-length")
+                .and_then(|length: Option<u64>| async move {
+                    match length {
+                        Some(length) if length > MAX_IMAGES_BODY => {
+                            Err(warp::reject::custom(BodyTooLarge))
+                        }
+                        _ => Ok(()),
+                    }
+                })
+                .untuple_one(),
+        )
+        .and(
+            // Query parameters with a bare array of images, or a JSON body
+            // holding both; without any body the game gets the default deck.
+            has_body(false)
+                .and(warp::query::<CreateQuery>())
+                .map(|query: CreateQuery| (query, None))
+                .untuple_one()
+                .or(has_body(true)
+                    .and(has_query(true))
+                    .and(warp::query::<CreateQuery>())
+                    .and(warp::body::content_length_limit(MAX_IMAGES_BODY))
+                    .and(warp::body::json::<Vec<String>>())
+                    .map(|query: CreateQuery, images| (query, Some(images)))
+                    .untuple_one())
+                .unify()
+                .or(has_body(true)
+                    .and(has_query(false))
+                    .and(warp::body::content_length_limit(MAX_IMAGES_BODY))
+                    .and(warp::body::json::<CreateBody>())
+                    .map(|body: CreateBody| (body.query, body.images))
+                    .untuple_one())
+                .unify(),
+        )
+        .and(store.clone())
+        .and_then(create);
+
+    let delete_route = warp::path("delete")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(admin_token())
+        .and(warp::query::<GameQuery>())
+        .and(store.clone())
+        .and_then(delete);
+
+    let restart_route = warp::path("restart")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(admin_token())
+        .and(warp::query::<GameQuery>())
+        .and(store.clone())
+        .and_then(restart);
+
+    let pause_route = warp::path("pause")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(admin_token())
+        .and(warp::query::<GameQuery>())
+        .and(store.clone())
+        .and_then(pause);
+
+    let resume_route = warp::path("resume")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(admin_token())
+        .and(warp::query::<GameQuery>())
+        .and(store.clone())
+        .and_then(resume);
+
+    let board_route = warp::path("board")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(admin_token())
+        .and(warp::query::<GameQuery>())
+        .and(store.clone())
+        .and_then(board);
+
+    let card_route = warp::path!("card" / usize)
+        .and(warp::get())
+        .and(admin_token())
+        .and(warp::query::<GameQuery>())
+        .and(store.clone())
+        .and_then(card);
+
+    let history_route = warp::path("history")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(admin_token())
+        .and(warp::query::<GameQuery>())
+        .and(store.clone())
+        .and_then(history);
+
+    let join_route = warp::path("join")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(rate_limited.clone())
+        .and(query_or_json::<JoinQuery>())
+        .and(store.clone())
+        .and_then(join);
+
+    let leave_route = warp::path("leave")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::cookie("memory_token"))
+        .and(warp::query::<GameQuery>())
+        .and(store.clone())
+        .and_then(leave);
+
+    let kick_route = warp::path("kick")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(admin_token())
+        .and(warp::query::<KickQuery>())
+        .and(store.clone())
+        .and_then(kick);
+
+    let transfer_admin_route = warp::path("transfer_admin")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(admin_token())
+        .and(warp::query::<TransferAdminQuery>())
+        .and(store.clone())
+        .and_then(transfer_admin);
+
+    let claim_admin_route = warp::path("claim_admin")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::query::<GameQuery>())
+        .and(warp::body::content_length_limit(MAX_ACTION_BODY))
+        .and(warp::body::json::<ClaimAdminBody>())
+        .and(store.clone())
+        .and_then(claim_admin);
+
+    let game_route = warp::path("game")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::cookie("memory_token"))
+        .and(warp::query::<GameQuery>())
+        .and(warp::header::optional::<u64>("last-event-id"))
+        .and(store.clone())
+        .and_then(game_message);
+
+    let state_route = warp::path("state")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::cookie("memory_token"))
+        .and(warp::query::<GameQuery>())
+        .and(store.clone())
+        .and_then(state);
+
+    let turn_route = warp::path("turn")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::query::<GameQuery>())
+        .and(store.clone())
+        .and_then(turn);
+
+    let config_route = warp::path("config")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::query::<GameQuery>())
+        .and(store.clone())
+        .and_then(config);
+
+    let leaderboard_route = warp::path("leaderboard")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::query::<LeaderboardQuery>())
+        .and(store.clone())
+        .and_then(leaderboard);
+
+    let ws_route = warp::path("ws")
+        .and(warp::path::end())
+        .and(warp::cookie("memory_token"))
+        .and(warp::query::<GameQuery>())
+        .and(warp::ws())
+        .and(store.clone())
+        .and_then(ws);
+
+    let watch_route = warp::path("watch")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::query::<GameQuery>())
+        .and(store.clone())
+        .and_then(watch);
+
+    let promote_route = warp::path("promote")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::cookie("spectator_token"))
+        .and(query_or_json::<JoinQuery>())
+        .and(store.clone())
+        .and_then(promote);
+
+    let ready_route = warp::path("ready")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::cookie("memory_token"))
+        .and(warp::query::<GameQuery>())
+        .and(store.clone())
+        .and_then(ready);
+
+    let unready_route = warp::path("unready")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::cookie("memory_token"))
+        .and(warp::query::<GameQuery>())
+        .and(store.clone())
+        .and_then(unready);
+
+    let skip_route = warp::path("skip")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::cookie("memory_token"))
+        .and(warp::query::<GameQuery>())
+        .and(store.clone())
+        .and_then(skip);
+
+    let peek_route = warp::path("peek")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::cookie("memory_token"))
+        .and(warp::query::<GameQuery>())
+        .and(store.clone())
+        .and_then(peek);
+
+    let unflip_route = warp::path("unflip")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::cookie("memory_token"))
+        .and(warp::query::<GameQuery>())
+        .and(store.clone())
+        .and_then(unflip);
+
+    let chat_route = warp::path("chat")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::cookie("memory_token"))
+        .and(warp::query::<ChatQuery>())
+        .and(store.clone())
+        .and_then(chat);
+
+    let pick_card_route = warp::path("pick_card")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::cookie("memory_token"))
+        .and(query_or_json::<PickQuery>())
+        .and(store.clone())
+        .and_then(pick_card);
+
+    // Boxing each group erases its nested `Or` types, which otherwise
+    // outgrow the compiler's recursion limit.
+    let server_routes = health_route
+        .or(metrics_route)
+        .or(games_route)
+        .or(ping_route)
+        .or(key_route)
+        .or(rotate_key_route)
+        .or(announce_route)
+        .or(admin_stream_route)
+        .map(|reply| Box::new(reply) as Box<dyn Reply>)
+        .boxed();
+
+    let lobby_routes = create_route
+        .or(delete_route)
+        .or(restart_route)
+        .or(pause_route)
+        .or(resume_route)
+        .or(history_route)
+        .or(board_route)
+        .or(card_route)
+        .or(join_route)
+        .or(leave_route)
+        .or(kick_route)
+        .or(transfer_admin_route)
+        .or(claim_admin_route)
+        .map(|reply| Box::new(reply) as Box<dyn Reply>)
+        .boxed();
+
+    let game_routes = game_route
+        .or(state_route)
+        .or(turn_route)
+        .or(config_route)
+        .or(leaderboard_route)
+        .or(watch_route)
+        .or(promote_route)
+        .or(ws_route)
+        .or(ready_route)
+        .or(unready_route)
+        .or(pick_card_route)
+        .or(skip_route)
+        .or(peek_route)
+        .or(unflip_route)
+        .or(chat_route)
+        .map(|reply| Box::new(reply) as Box<dyn Reply>)
+        .boxed();
+
+    server_routes.or(lobby_routes).or(game_routes)
+}
+
+/// Reads `T` from the query string or, if the request has a body, from JSON
+/// of the same shape, which keeps names and card indices out of access logs.
+/// A body that is too large or doesn't parse is rejected as such.
+fn query_or_json<T>() -> impl Filter<Extract = (T,), Error = Rejection> + Clone
+where
+    T: DeserializeOwned + Send + 'static,
+{
+    has_body(false)
+        .and(warp::query::<T>())
+        .or(has_body(true)
+            .and(warp::body::content_length_limit(MAX_ACTION_BODY))
+            .and(warp::body::json::<T>()))
+        .unify()
+}
+
+/// Passes only requests that do or don't have a body, going by their
+/// `Content-Length` or, for chunked bodies, `Transfer-Encoding`. Others are
+/// not found, so the next `or` branch is tried.
+fn has_body(expected: bool) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<u64>("content-length")
+        .and(warp::header::optional::<String>("transfer-encoding"))
+        .and_then(
+            move |length: Option<u64>, encoding: Option<String>| async move {
+                let present = length.is_some_and(|length| length > 0) || encoding.is_some();
+                if present == expected {
+                    Ok(())
+                } else {
+                    Err(warp::reject::not_found())
+                }
+            },
+        )
+        .untuple_one()
+}
+
+/// Like `has_body`, for a non-empty query string.
+fn has_query(expected: bool) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::query::raw()
+        .map(|query: String| !query.is_empty())
+        .or(warp::any().map(|| false))
+        .unify()
+        .and_then(move |present: bool| async move {
+            if present == expected {
+                Ok(())
+            } else {
+                Err(warp::reject::not_found())
+            }
+        })
+        .untuple_one()
+}
+
+/// The admin token of the game named in the query, read from that game's
+/// own cookie, see `admin_cookie_name`.
+fn admin_token() -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
+    warp::query::<GameQuery>()
+        .and(warp::header::optional::<String>("cookie"))
+        .and_then(|query: GameQuery, cookies: Option<String>| async move {
+            let name = admin_cookie_name(&query.id);
+            cookies
+                .as_deref()
+                .and_then(|cookies| find_cookie(cookies, &name))
+                .ok_or_else(|| warp::reject::custom(InvalidGameAdmin))
+        })
+}
+
+/// The value of cookie `name` in a `Cookie` header.
+fn find_cookie(cookies: &str, name: &str) -> Option<String> {
+    cookies.split(';').find_map(|cookie| {
+        let (key, value) = cookie.trim().split_once('=')?;
+        (key == name).then(|| value.to_owned())
+    })
+}
@@ -0,0 +1,1054 @@
+mod common;
+
+use common::{json, next_event, next_message, request, set_cookie, TestApp, MASTER_KEY};
+use std::time::Duration;
+
+use memory_backend::handler;
+use memory_backend::memory::{GameConfig, GameState, RateLimit};
+use memory_backend::queries::GameQuery;
+use memory_backend::reject::handle_rejection;
+use memory_backend::routes;
+use warp::http::StatusCode;
+use warp::hyper::body::HttpBody;
+use warp::{Filter, Reply};
+
+#[tokio::test]
+async fn create_join_ready_and_pick() {
+    let app = TestApp::new();
+    app.create_game("room").await;
+    let tokens = app.join_players("room", 2).await;
+
+    let res = app
+        .post("/ready?id=room", Some(("memory_token", &tokens[0])))
+        .await;
+    assert_eq!(json(&res), "Success");
+    let res = app
+        .post("/ready?id=room", Some(("memory_token", &tokens[1])))
+        .await;
+    assert_eq!(json(&res), "Started");
+
+    let res = app.get("/turn?id=room", None).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let name = json(&res)["name"].as_str().unwrap().to_owned();
+    let (current, other) = if name == "player0" {
+        (&tokens[0], &tokens[1])
+    } else {
+        (&tokens[1], &tokens[0])
+    };
+
+    let res = app
+        .post("/pick_card?id=room&card=0", Some(("memory_token", other)))
+        .await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    assert_eq!(json(&res)["code"], "NOT_YOUR_TURN");
+
+    let res = app
+        .post("/pick_card?id=room&card=0", Some(("memory_token", current)))
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(json(&res), "Success");
+
+    let res = app
+        .get("/state?id=room", Some(("memory_token", current)))
+        .await;
+    let state = json(&res);
+    assert_eq!(state["game_state"], "Running");
+    assert_eq!(state["turn"], true);
+    assert_eq!(state["flipped"][0][0], 0);
+}
+
+#[tokio::test]
+async fn create_needs_the_master_key_and_a_free_id() {
+    let app = TestApp::new();
+
+    let res = app.post("/create?id=room", None).await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(json(&res)["code"], "MISSING_COOKIE");
+
+    let res = app
+        .post("/create?id=room", Some(("master_key", "wrong")))
+        .await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(json(&res)["code"], "INVALID_MASTER_KEY");
+
+    app.create_game("room").await;
+    let res = app
+        .post("/create?id=room", Some(("master_key", MASTER_KEY)))
+        .await;
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+    assert_eq!(json(&res)["code"], "ALREADY_EXISTS");
+}
+
+#[tokio::test]
+async fn join_rejects_unknown_games_and_taken_names() {
+    let app = TestApp::new();
+
+    let res = app.post("/join?id=nowhere&name=alice", None).await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    assert_eq!(json(&res)["code"], "NO_GAME_EXISTS");
+
+    app.create_game("room").await;
+    app.join("room", "alice").await;
+    let res = app.post("/join?id=room&name=alice", None).await;
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+    assert_eq!(json(&res)["code"], "NAME_TAKEN");
+}
+
+#[tokio::test]
+async fn started_games_cannot_be_joined_or_readied_again() {
+    let app = TestApp::new();
+    app.create_game("room").await;
+    let tokens = app.join_players("room", 2).await;
+    app.start("room", &tokens).await;
+
+    let res = app.post("/join?id=room&name=late", None).await;
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+    let res = app
+        .post("/ready?id=room", Some(("memory_token", &tokens[0])))
+        .await;
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+    assert_eq!(json(&res)["code"], "ALREADY_RUNNING");
+}
+
+#[tokio::test]
+async fn only_games_that_are_not_under_way_can_be_restarted() {
+    let app = TestApp::new();
+    let admin_token = app.create_game("room").await;
+    let tokens = app.join_players("room", 2).await;
+    app.start("room", &tokens).await;
+    let restart = || app.post("/restart?id=room", Some(("admin_token_room", &admin_token)));
+
+    let res = restart().await;
+    assert_eq!(json(&res)["code"], "ALREADY_RUNNING");
+    app.post("/pause?id=room", Some(("admin_token_room", &admin_token)))
+        .await;
+    let res = restart().await;
+    assert_eq!(json(&res)["code"], "ALREADY_RUNNING");
+    app.store.write().await.get_game_mut("room").unwrap().state = GameState::Starting;
+    let res = restart().await;
+    assert_eq!(json(&res)["code"], "ALREADY_RUNNING");
+
+    app.store.write().await.get_game_mut("room").unwrap().state = GameState::Finished;
+    assert_eq!(restart().await.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn rotated_master_keys_must_be_strong() {
+    let app = TestApp::new();
+    app.store.write().await.min_key_length = 16;
+    let rotate = |current: &str, key: &str| {
+        request("POST", "/rotate_key", Some(("master_key", current)))
+            .json(&serde_json::json!({ "key": key }))
+    };
+
+    let res = app.send(rotate(MASTER_KEY, "aaaaaaaaaaaaaaaaaaaa")).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(json(&res)["code"], "WEAK_KEY");
+    let res = app.send(rotate(MASTER_KEY, "Sh0rt-key")).await;
+    assert_eq!(json(&res)["code"], "WEAK_KEY");
+
+    let strong = "Tr0ub4dor-and-3-horses";
+    let res = app.send(rotate(MASTER_KEY, strong)).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(set_cookie(&res, "master_key").as_deref(), Some(strong));
+    let res = app.send(rotate(MASTER_KEY, strong)).await;
+    assert_eq!(json(&res)["code"], "INVALID_MASTER_KEY");
+
+    app.store.write().await.allow_weak_key = true;
+    let res = app.send(rotate(strong, "weak")).await;
+    assert_eq!(res.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn create_is_rejected_once_max_games_are_open() {
+    let app = TestApp::new();
+    app.store.write().await.max_games = 2;
+    let admin_token = app.create_game("one").await;
+    app.create_game("two").await;
+
+    let res = app
+        .post("/create?id=three", Some(("master_key", MASTER_KEY)))
+        .await;
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(json(&res)["code"], "TOO_MANY_GAMES");
+
+    app.post("/delete?id=one", Some(("admin_token_one", &admin_token)))
+        .await;
+    app.create_game("three").await;
+
+    // Finished games wait to be reaped but leave room for new ones.
+    let mut lock = app.store.write().await;
+    lock.get_game_mut("two").unwrap().state = GameState::Finished;
+    drop(lock);
+    app.create_game("four").await;
+}
+
+#[tokio::test]
+async fn every_game_keeps_its_own_admin_cookie() {
+    let app = TestApp::new();
+    let first = app.create_game("one").await;
+    let second = app.create_game("two").await;
+    let cookies = format!("admin_token_one={}; admin_token_two={}", first, second);
+
+    for id in ["one", "two"] {
+        let res = app
+            .send(request("GET", &format!("/board?id={}", id), None).header("cookie", &cookies))
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+    let res = app
+        .get("/board?id=two", Some(("admin_token_one", &first)))
+        .await;
+    assert_eq!(json(&res)["code"], "INVALID_GAME_ADMIN");
+}
+
+#[tokio::test]
+async fn cookies_drop_secure_only_in_dev_mode() {
+    let app = TestApp::new();
+    app.create_game("room").await;
+
+    let res = app.post("/join?id=room&name=alice", None).await;
+    let cookie = res.headers()["set-cookie"].to_str().unwrap();
+    assert!(cookie.ends_with("; SameSite=None; Secure; HttpOnly"));
+
+    app.store.write().await.dev_mode = true;
+    let res = app.post("/join?id=room&name=bob", None).await;
+    let cookie = res.headers()["set-cookie"].to_str().unwrap();
+    assert!(cookie.ends_with("; SameSite=Lax; HttpOnly"));
+    let token = common::set_cookie(&res, "memory_token").unwrap();
+
+    let res = app
+        .post("/leave?id=room", Some(("memory_token", &token)))
+        .await;
+    let cookie = res.headers()["set-cookie"].to_str().unwrap();
+    assert_eq!(cookie, "memory_token=0; Max-Age=0; SameSite=Lax; HttpOnly");
+}
+
+#[tokio::test]
+async fn active_player_can_take_back_the_first_card() {
+    let app = TestApp::new();
+    app.create_game("room").await;
+    let tokens = app.join_players("room", 2).await;
+    app.start("room", &tokens).await;
+    let name = json(&app.get("/turn?id=room", None).await)["name"].clone();
+    let (current, other) = if name == "player0" {
+        (&tokens[0], &tokens[1])
+    } else {
+        (&tokens[1], &tokens[0])
+    };
+
+    let res = app
+        .post("/unflip?id=room", Some(("memory_token", current)))
+        .await;
+    assert_eq!(json(&res)["code"], "NOTHING_TO_UNDO");
+
+    app.post("/pick_card?id=room&card=0", Some(("memory_token", current)))
+        .await;
+    let res = app
+        .post("/unflip?id=room", Some(("memory_token", other)))
+        .await;
+    assert_eq!(json(&res)["code"], "NOT_YOUR_TURN");
+    let res = app
+        .post("/unflip?id=room", Some(("memory_token", current)))
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let state = json(
+        &app.get("/state?id=room", Some(("memory_token", current)))
+            .await,
+    );
+    assert_eq!(state["flipped"], serde_json::json!([]));
+    assert_eq!(state["turn"], true);
+
+    // Only one card per turn can be taken back.
+    app.post("/pick_card?id=room&card=0", Some(("memory_token", current)))
+        .await;
+    let res = app
+        .post("/unflip?id=room", Some(("memory_token", current)))
+        .await;
+    assert_eq!(json(&res)["code"], "UNDO_USED");
+
+    // A complete pair can no longer be taken back.
+    let lock = app.store.read().await;
+    let cards = &lock.get_game("room").unwrap().cards;
+    let other_card = (1..cards.len())
+        .find(|&i| cards[i].img_path == cards[0].img_path)
+        .unwrap();
+    drop(lock);
+    app.post(
+        &format!("/pick_card?id=room&card={}", other_card),
+        Some(("memory_token", current)),
+    )
+    .await;
+    let res = app
+        .post("/unflip?id=room", Some(("memory_token", current)))
+        .await;
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+}
+
+#[tokio::test]
+async fn event_stream_starts_with_a_snapshot() {
+    let app = TestApp::new();
+    app.create_game("room").await;
+    let tokens = app.join_players("room", 2).await;
+    app.start("room", &tokens).await;
+    // Only the player whose turn it is gets to flip the card.
+    app.post(
+        "/pick_card?id=room&card=0",
+        Some(("memory_token", &tokens[0])),
+    )
+    .await;
+    app.post(
+        "/pick_card?id=room&card=0",
+        Some(("memory_token", &tokens[1])),
+    )
+    .await;
+
+    let query = GameQuery {
+        id: "room".to_owned(),
+    };
+    let reply = handler::game_message(tokens[0].clone(), query, None, app.store.clone())
+        .await
+        .unwrap_or_else(|_| panic!("game_message rejected"));
+    let mut body = reply.into_response().into_body();
+    let (name, state) = next_event(&mut body).await;
+
+    assert_eq!(name, "init");
+    assert_eq!(state["flipped"][0][0], 0);
+}
+
+#[tokio::test]
+async fn admin_stream_reports_games_and_players() {
+    let app = TestApp::new();
+    app.create_game("first").await;
+
+    let Err(_) = handler::admin_stream("wrong".to_owned(), app.store.clone()).await else {
+        panic!("admin stream opened without the master key");
+    };
+    let reply = handler::admin_stream(MASTER_KEY.to_owned(), app.store.clone())
+        .await
+        .unwrap_or_else(|_| panic!("admin_stream rejected"));
+    let mut body = reply.into_response().into_body();
+
+    let (name, init) = next_event(&mut body).await;
+    assert_eq!(name, "init");
+    assert_eq!(init["games"][0]["id"], "first");
+
+    app.create_game("second").await;
+    let (name, created) = next_event(&mut body).await;
+    assert_eq!(name, "gameCreated");
+    assert_eq!(created["id"], "second");
+
+    let token = app.join("second", "alice").await;
+    let (name, joined) = next_event(&mut body).await;
+    assert_eq!(name, "playerJoined");
+    assert_eq!(
+        joined,
+        serde_json::json!({ "id": "second", "name": "alice" })
+    );
+
+    app.post("/leave?id=second", Some(("memory_token", &token)))
+        .await;
+    let (name, left) = next_event(&mut body).await;
+    assert_eq!(name, "playerLeft");
+    assert_eq!(left["name"], "alice");
+
+    drop(body);
+    tokio::task::yield_now().await;
+    app.create_game("third").await;
+    assert!(app.store.read().await.admin_senders.is_empty());
+}
+
+#[tokio::test]
+async fn create_lists_malformed_image_urls() {
+    let app = TestApp::new();
+    let images = serde_json::json!(["https://example.com/a.png", "file:///etc/passwd"]);
+    let res = app
+        .send(request("POST", "/create?id=room", Some(("master_key", MASTER_KEY))).json(&images))
+        .await;
+
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    let body = json(&res);
+    assert_eq!(body["code"], "INVALID_IMAGES");
+    assert_eq!(
+        body["invalid_urls"],
+        serde_json::json!(["file:///etc/passwd"])
+    );
+    assert!(app.store.read().await.games.is_empty());
+}
+
+#[tokio::test]
+async fn create_rejects_malformed_bodies() {
+    let app = TestApp::new();
+    let create = || request("POST", "/create?id=room", Some(("master_key", MASTER_KEY)));
+
+    let res = app
+        .send(create().body("[\"https://example.com/a.png\""))
+        .await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(json(&res)["code"], "INVALID_BODY");
+
+    // With query parameters the body is the bare list of images.
+    let images = serde_json::json!({ "images": ["https://example.com/a.png"] });
+    let res = app.send(create().json(&images)).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(json(&res)["code"], "INVALID_BODY");
+
+    // Without a length the body can't be checked against the limit.
+    let res = app
+        .post_chunked(
+            "/create?id=room",
+            ("master_key", MASTER_KEY),
+            "[\"https://example.com/a.png\"]",
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::LENGTH_REQUIRED);
+    assert_eq!(json(&res)["code"], "LENGTH_REQUIRED");
+    assert!(app.store.read().await.games.is_empty());
+}
+
+#[tokio::test]
+async fn oversized_bodies_are_rejected_as_such() {
+    let app = TestApp::new();
+    let images = vec!["https://example.com/a.png".to_owned(); 4096];
+    let res = app
+        .send(request("POST", "/create?id=room", Some(("master_key", MASTER_KEY))).json(&images))
+        .await;
+    assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(json(&res)["code"], "BODY_TOO_LARGE");
+    assert!(app.store.read().await.games.is_empty());
+
+    app.create_game("room").await;
+    let name = "a".repeat(8 * 1024);
+    let res = app
+        .send(request("POST", "/join", None).json(&serde_json::json!({
+            "id": "room",
+            "name": name,
+        })))
+        .await;
+    assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(json(&res)["code"], "BODY_TOO_LARGE");
+
+    let res = app
+        .send(request("POST", "/join", None).body("{\"id\": \"room\""))
+        .await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(json(&res)["code"], "INVALID_BODY");
+}
+
+/// Moves the paused clock on and lets the woken timers run.
+async fn advance(duration: Duration) {
+    tokio::time::advance(duration).await;
+    for _ in 0..10 {
+        tokio::task::yield_now().await;
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn turned_cards_stay_visible_for_the_reveal_delay() {
+    let app = TestApp::new();
+    app.store.write().await.turn_timeout = Duration::from_secs(10);
+    let res = app
+        .post(
+            "/create?id=room&reveal_ms=500",
+            Some(("master_key", MASTER_KEY)),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let tokens = app.join_players("room", 2).await;
+    app.start("room", &tokens).await;
+    let name = json(&app.get("/turn?id=room", None).await)["name"].clone();
+    let (current, other) = if name == "player0" {
+        (&tokens[0], &tokens[1])
+    } else {
+        (&tokens[1], &tokens[0])
+    };
+    let flipped = || async {
+        let state = json(
+            &app.get("/state?id=room", Some(("memory_token", current)))
+                .await,
+        );
+        state["flipped"].as_array().unwrap().len()
+    };
+
+    // A mismatch is hidden after the reveal delay.
+    let lock = app.store.read().await;
+    let cards = &lock.get_game("room").unwrap().cards;
+    let mismatch = (1..cards.len())
+        .find(|&i| cards[i].img_path != cards[0].img_path)
+        .unwrap();
+    drop(lock);
+    app.post("/pick_card?id=room&card=0", Some(("memory_token", current)))
+        .await;
+    app.post(
+        &format!("/pick_card?id=room&card={}", mismatch),
+        Some(("memory_token", current)),
+    )
+    .await;
+    // Let the hide timer start before moving the clock.
+    advance(Duration::ZERO).await;
+    advance(Duration::from_millis(499)).await;
+    assert_eq!(flipped().await, 2);
+    advance(Duration::from_millis(2)).await;
+    assert_eq!(flipped().await, 0);
+    assert_ne!(json(&app.get("/turn?id=room", None).await)["name"], name);
+
+    // So is a card left showing when the turn times out.
+    app.post("/pick_card?id=room&card=0", Some(("memory_token", other)))
+        .await;
+    advance(Duration::from_secs(10)).await;
+    assert_eq!(flipped().await, 1);
+    advance(Duration::from_millis(500)).await;
+    assert_eq!(flipped().await, 0);
+    assert_eq!(json(&app.get("/turn?id=room", None).await)["name"], name);
+}
+
+#[tokio::test]
+async fn unknown_paths_list_the_endpoints() {
+    let app = TestApp::new();
+
+    for res in [
+        app.get("/nothing", None).await,
+        app.post("/nothing", Some(("memory_token", "token"))).await,
+        app.get("/state/extra?id=room", None).await,
+    ] {
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let body = json(&res);
+        assert_eq!(body["code"], "NOT_FOUND");
+        let endpoints = body["endpoints"].as_array().unwrap();
+        assert!(endpoints.contains(&serde_json::json!("POST /pick_card")));
+    }
+
+    // Known paths still answer with the reason they were refused.
+    let res = app.get("/state?id=room", None).await;
+    assert_eq!(json(&res)["code"], "MISSING_COOKIE");
+    let res = app.get("/ready?id=room", None).await;
+    assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+}
+
+/// `METHOD /path` of every route built in `source`, with parameters as `{}`.
+fn mounted_routes(source: &str) -> Vec<String> {
+    let mut routes = Vec::new();
+    for block in source.split("_route = ").skip(1) {
+        let block = &block[..block.find(';').unwrap()];
+        let Some(args) = block
+            .strip_prefix("warp::path!(")
+            .or_else(|| block.strip_prefix("warp::path("))
+        else {
+            continue;
+        };
+        let mut path: String = args[..args.find(')').unwrap()]
+            .split('/')
+            .map(|segment| match segment.trim().strip_prefix('"') {
+                Some(literal) => format!("/{}", literal.trim_end_matches('"')),
+                None => "/{}".to_owned(),
+            })
+            .collect();
+        // A directory route serves the files below it.
+        if block.contains("warp::fs::dir") {
+            path.push_str("/{}");
+        }
+        let method = if block.contains("warp::post()") {
+            "POST"
+        } else {
+            "GET"
+        };
+        routes.push(format!("{} {}", method, path));
+    }
+    routes
+}
+
+#[test]
+fn every_mounted_route_is_listed() {
+    let mut mounted = mounted_routes(include_str!("../src/routes.rs"));
+    mounted.extend(mounted_routes(include_str!("../src/main.rs")));
+    mounted.sort();
+    let mut listed: Vec<String> = routes::ENDPOINTS
+        .iter()
+        .map(|endpoint| {
+            endpoint
+                .split('/')
+                .map(|segment| {
+                    if segment.starts_with('{') {
+                        "{}"
+                    } else {
+                        segment
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join("/")
+        })
+        .collect();
+    listed.sort();
+
+    assert_eq!(mounted, listed);
+}
+
+#[tokio::test]
+async fn admin_can_inspect_a_single_card() {
+    let app = TestApp::new();
+    let admin_token = app.create_game("room").await;
+
+    let res = app
+        .get("/card/3?id=room", Some(("admin_token_room", &admin_token)))
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let lock = app.store.read().await;
+    let card = &lock.get_game("room").unwrap().cards[3];
+    assert_eq!(
+        json(&res),
+        serde_json::json!({
+            "img_path": card.img_path,
+            "is_external": true,
+            "flipped": false,
+            "gone": false,
+        })
+    );
+    drop(lock);
+
+    let res = app
+        .get(
+            "/card/1000?id=room",
+            Some(("admin_token_room", &admin_token)),
+        )
+        .await;
+    assert_eq!(json(&res)["code"], "INVALID_CARD");
+    let res = app
+        .get("/card/3?id=room", Some(("admin_token_room", "wrong")))
+        .await;
+    assert_eq!(json(&res)["code"], "INVALID_GAME_ADMIN");
+}
+
+#[tokio::test]
+async fn resumed_streams_replay_missed_events() {
+    let app = TestApp::new();
+    app.create_game("room").await;
+    let token = app.join("room", "alice").await;
+    app.join("room", "bob").await;
+    let open = |last_event_id| {
+        let query = GameQuery {
+            id: "room".to_owned(),
+        };
+        handler::game_message(token.clone(), query, last_event_id, app.store.clone())
+    };
+
+    // Every broadcast since joining, in order, and with its id.
+    let reply = open(Some(0)).await.unwrap_or_else(|_| panic!("rejected"));
+    let mut body = reply.into_response().into_body();
+    let chunk = body.data().await.unwrap().unwrap();
+    let frame = std::str::from_utf8(&chunk).unwrap();
+    assert!(frame.contains("event:playerJoined\n"), "{}", frame);
+    assert!(frame.contains("id:1\n"), "{}", frame);
+    assert_eq!(next_event(&mut body).await.0, "leaderboard");
+
+    // An id the log does not know gets a snapshot instead.
+    let reply = open(Some(1_000_000))
+        .await
+        .unwrap_or_else(|_| panic!("rejected"));
+    let mut body = reply.into_response().into_body();
+    assert_eq!(next_event(&mut body).await.0, "init");
+}
+
+#[tokio::test]
+async fn picks_wait_until_a_mismatch_is_hidden() {
+    let app = TestApp::new();
+    let res = app
+        .post(
+            "/create?id=room&reveal_ms=10000",
+            Some(("master_key", MASTER_KEY)),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let tokens = app.join_players("room", 2).await;
+    app.start("room", &tokens).await;
+    let name = json(&app.get("/turn?id=room", None).await)["name"].clone();
+    let (current, other) = if name == "player0" {
+        (&tokens[0], &tokens[1])
+    } else {
+        (&tokens[1], &tokens[0])
+    };
+
+    let lock = app.store.read().await;
+    let cards = &lock.get_game("room").unwrap().cards;
+    let mismatch = (1..cards.len())
+        .find(|&i| cards[i].img_path != cards[0].img_path)
+        .unwrap();
+    let free = (1..cards.len()).find(|&i| i != mismatch).unwrap();
+    drop(lock);
+    app.post("/pick_card?id=room&card=0", Some(("memory_token", current)))
+        .await;
+    app.post(
+        &format!("/pick_card?id=room&card={}", mismatch),
+        Some(("memory_token", current)),
+    )
+    .await;
+
+    for token in [current, other] {
+        let res = app
+            .post(
+                &format!("/pick_card?id=room&card={}", free),
+                Some(("memory_token", token)),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        assert_eq!(json(&res)["code"], "RESOLVING_MATCH");
+    }
+}
+
+#[tokio::test]
+async fn config_reports_the_scoring_profile() {
+    let app = TestApp::new();
+    for (query, scoring) in [
+        ("id=plain", "Classic"),
+        ("id=streaky&streak_bonus=2", "Streak"),
+        ("id=quick&scoring=TimedBonus", "TimedBonus"),
+    ] {
+        let res = app
+            .post(
+                &format!("/create?{}", query),
+                Some(("master_key", MASTER_KEY)),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let id = &query[3..query.find('&').unwrap_or(query.len())];
+        let config = json(&app.get(&format!("/config?id={}", id), None).await);
+        assert_eq!(config["scoring"], scoring);
+    }
+}
+
+/// Advances one second at a time, so timers set by other timers fire too.
+async fn advance_secs(secs: u64) {
+    for _ in 0..secs {
+        advance(Duration::from_secs(1)).await;
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn lobbies_start_once_the_lobby_timeout_passes() {
+    let app = TestApp::new();
+    app.store.write().await.lobby_timeout = Duration::from_secs(30);
+    app.create_game("room").await;
+    let state = |app: &TestApp| {
+        let store = app.store.clone();
+        async move { store.read().await.get_game("room").unwrap().state }
+    };
+
+    // A lone player keeps waiting, and the next round counts from then on.
+    let first = app.join("room", "player0").await;
+    advance(Duration::ZERO).await;
+    advance_secs(30).await;
+    assert!(matches!(state(&app).await, GameState::Lobby));
+    let second = app.join("room", "player1").await;
+    app.post("/ready?id=room", Some(("memory_token", &first)))
+        .await;
+
+    advance_secs(29).await;
+    assert!(matches!(state(&app).await, GameState::Lobby));
+    advance_secs(1).await;
+    assert!(matches!(state(&app).await, GameState::Running));
+    let game = app.store.read().await;
+    let game = game.get_game("room").unwrap();
+    assert!(game.players[&second].ready);
+}
+
+#[tokio::test(start_paused = true)]
+async fn restarted_lobbies_start_once_the_lobby_timeout_passes() {
+    let app = TestApp::new();
+    app.store.write().await.lobby_timeout = Duration::from_secs(30);
+    let admin_token = app.create_game("room").await;
+    let tokens = app.join_players("room", 2).await;
+    app.start("room", &tokens).await;
+    let state = |app: &TestApp| {
+        let store = app.store.clone();
+        async move { store.read().await.get_game("room").unwrap().state }
+    };
+
+    // The timer armed by the first join gives up once the game is running.
+    advance(Duration::ZERO).await;
+    advance_secs(30).await;
+    app.store.write().await.get_game_mut("room").unwrap().state = GameState::Finished;
+    let res = app
+        .post("/restart?id=room", Some(("admin_token_room", &admin_token)))
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    app.post("/ready?id=room", Some(("memory_token", &tokens[0])))
+        .await;
+
+    advance(Duration::ZERO).await;
+    advance_secs(29).await;
+    assert!(matches!(state(&app).await, GameState::Lobby));
+    advance_secs(1).await;
+    assert!(matches!(state(&app).await, GameState::Running));
+}
+
+#[tokio::test(start_paused = true)]
+async fn deleted_lobbies_take_their_timeout_along() {
+    let app = TestApp::new();
+    app.store.write().await.lobby_timeout = Duration::from_secs(30);
+    let admin_token = app.create_game("room").await;
+    app.join_players("room", 2).await;
+    advance(Duration::ZERO).await;
+    app.post("/delete?id=room", Some(("admin_token_room", &admin_token)))
+        .await;
+
+    advance_secs(20).await;
+    app.create_game("room").await;
+    app.join_players("room", 2).await;
+    advance(Duration::ZERO).await;
+    let state = |app: &TestApp| {
+        let store = app.store.clone();
+        async move { store.read().await.get_game("room").unwrap().state }
+    };
+
+    // The first timer runs out, but only the second one may start the game.
+    advance_secs(10).await;
+    assert!(matches!(state(&app).await, GameState::Lobby));
+    advance_secs(20).await;
+    assert!(matches!(state(&app).await, GameState::Running));
+}
+
+#[tokio::test]
+async fn games_use_the_store_defaults_unless_overridden() {
+    let app = TestApp::new();
+    app.store.write().await.defaults = GameConfig::default().board(4, 3).match_size(3).peeks(2);
+    app.create_game("plain").await;
+    let res = app
+        .post(
+            "/create?id=custom&columns=2&match_size=2",
+            Some(("master_key", MASTER_KEY)),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let plain = json(&app.get("/config?id=plain", None).await);
+    assert_eq!(
+        (plain["columns"].clone(), plain["rows"].clone()),
+        (4.into(), 3.into())
+    );
+    assert_eq!(plain["match_size"], 3);
+    let custom = json(&app.get("/config?id=custom", None).await);
+    assert_eq!(
+        (custom["columns"].clone(), custom["rows"].clone()),
+        (2.into(), 3.into())
+    );
+    assert_eq!(custom["match_size"], 2);
+    assert_eq!(custom["peeks"], 2);
+}
+
+#[tokio::test]
+async fn rapid_joins_from_one_address_are_limited() {
+    let app = TestApp::new();
+    app.store.write().await.rate_limit = RateLimit {
+        burst: 3,
+        interval: Duration::from_secs(60),
+    };
+    app.create_game("room").await;
+    let join = |name: &str, addr: &str| {
+        request("POST", &format!("/join?id=room&name={}", name), None)
+            .remote_addr(addr.parse().unwrap())
+    };
+
+    for i in 0..3 {
+        let res = app.send(join(&format!("p{}", i), "10.0.0.1:4000")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+    let res = app.send(join("p3", "10.0.0.1:4001")).await;
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(json(&res)["code"], "TOO_MANY_REQUESTS");
+
+    let res = app.send(join("p3", "10.0.0.2:4000")).await;
+    assert_eq!(res.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn actions_accept_json_bodies() {
+    let app = TestApp::new();
+    let images: Vec<String> = (0..3)
+        .map(|i| format!("https://example.com/{}.png", i))
+        .collect();
+    let res = app
+        .send(
+            request("POST", "/create", Some(("master_key", MASTER_KEY))).json(&serde_json::json!({
+                "id": "room",
+                "columns": 3,
+                "rows": 2,
+                "images": images,
+            })),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let config = json(&app.get("/config?id=room", None).await);
+    assert_eq!(config["size"], 6);
+
+    let mut tokens = Vec::new();
+    for name in ["alice", "bob"] {
+        let res = app
+            .send(request("POST", "/join", None).json(&serde_json::json!({
+                "id": "room",
+                "name": name,
+            })))
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        tokens.push(set_cookie(&res, "memory_token").unwrap());
+    }
+    app.start("room", &tokens).await;
+
+    let name = json(&app.get("/turn?id=room", None).await)["name"].clone();
+    let current = if name == "alice" {
+        &tokens[0]
+    } else {
+        &tokens[1]
+    };
+    let res = app
+        .send(
+            request("POST", "/pick_card", Some(("memory_token", current)))
+                .json(&serde_json::json!({ "id": "room", "card": 0 })),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let lock = app.store.read().await;
+    assert!(lock.get_game("room").unwrap().cards[0].flipped);
+}
+
+#[tokio::test]
+async fn reveal_multipliers_are_capped() {
+    let app = TestApp::new();
+    app.create_game("room").await;
+
+    let res = app
+        .post("/join?id=room&name=alice&reveal_multiplier=2", None)
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    for multiplier in ["0.5", "4"] {
+        let res = app
+            .post(
+                &format!("/join?id=room&name=bob&reveal_multiplier={}", multiplier),
+                None,
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+    let lock = app.store.read().await;
+    let game = lock.get_game("room").unwrap();
+    assert_eq!(game.players.len(), 1);
+    assert_eq!(game.players.values().next().unwrap().reveal_multiplier, 2.0);
+}
+
+#[tokio::test]
+async fn the_game_admin_can_hand_over_to_a_connected_player() {
+    let app = TestApp::new();
+    let old_token = app.create_game("room").await;
+    let tokens = app.join_players("room", 2).await;
+    let (sender, mut receiver) = tokio::sync::mpsc::channel(16);
+    app.store
+        .write()
+        .await
+        .get_game_mut("room")
+        .unwrap()
+        .players
+        .get_mut(&tokens[0])
+        .unwrap()
+        .add_sender(sender);
+
+    // player1 has no stream to receive the token on.
+    for name in ["nobody", "player1"] {
+        let res = app
+            .post(
+                &format!("/transfer_admin?id=room&name={}", name),
+                Some(("admin_token_room", &old_token)),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+    let res = app
+        .post(
+            "/transfer_admin?id=room&name=player0",
+            Some(("admin_token_room", &old_token)),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let mut new_token = None;
+    let mut changed = None;
+    while let Ok(event) = receiver.try_recv() {
+        match event.name.as_str() {
+            "adminToken" => new_token = event.data["admin_token"].as_str().map(str::to_owned),
+            "adminChanged" => changed = Some(event.data["name"].clone()),
+            _ => (),
+        }
+    }
+    assert_eq!(changed.unwrap(), "player0");
+    let new_token = new_token.expect("no adminToken event");
+    let claim = |admin_token: &str| {
+        request("POST", "/claim_admin?id=room", None)
+            .json(&serde_json::json!({ "admin_token": admin_token }))
+    };
+    let res = app.send(claim(&old_token)).await;
+    assert_eq!(json(&res)["code"], "INVALID_GAME_ADMIN");
+    let res = app.send(claim(&new_token)).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let cookie = set_cookie(&res, "admin_token_room").expect("no admin cookie");
+
+    let res = app
+        .get("/board?id=room", Some(("admin_token_room", &old_token)))
+        .await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    let res = app
+        .get("/board?id=room", Some(("admin_token_room", &cookie)))
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn websocket_errors_carry_a_code_and_message() {
+    let app = TestApp::new();
+    app.create_game("room").await;
+    let token = app.join("room", "alice").await;
+    let api = routes::api(app.store.clone()).recover(handle_rejection);
+    let mut client = warp::test::ws()
+        .path("/ws?id=room")
+        .header("cookie", format!("memory_token={}", token))
+        .handshake(api)
+        .await
+        .unwrap();
+    assert_eq!(next_message(&mut client).await["event"], "init");
+
+    client.send_text(r#"{"action":"pick","card":0}"#).await;
+    let error = next_message(&mut client).await;
+    assert_eq!(error["event"], "error");
+    assert_eq!(error["data"]["code"], "NOT_YET_RUNNING");
+    assert_eq!(error["data"]["error"], "Game is not running");
+
+    client.send_text("not json").await;
+    assert_eq!(
+        next_message(&mut client).await["data"]["code"],
+        "INVALID_MESSAGE"
+    );
+}
+
+#[tokio::test]
+async fn kicked_players_lose_their_websocket() {
+    let app = TestApp::new();
+    let admin_token = app.create_game("room").await;
+    let token = app.join("room", "alice").await;
+    let api = routes::api(app.store.clone()).recover(handle_rejection);
+    let mut client = warp::test::ws()
+        .path("/ws?id=room")
+        .header("cookie", format!("memory_token={}", token))
+        .handshake(api)
+        .await
+        .unwrap();
+    assert_eq!(next_message(&mut client).await["event"], "init");
+
+    let res = app
+        .post(
+            "/kick?id=room&name=alice",
+            Some(("admin_token_room", &admin_token)),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let closed = tokio::time::timeout(Duration::from_secs(5), async {
+        while client.recv().await.is_ok() {}
+    })
+    .await;
+    assert!(closed.is_ok(), "the socket stayed open");
+}
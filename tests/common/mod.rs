@@ -0,0 +1,172 @@
+//! A harness that serves the full API from an in-memory store, for tests
+//! that go through HTTP instead of calling `Memory` directly.
+
+#![allow(dead_code)]
+
+use memory_backend::memory::{GameConfig, MemoryStore, Store};
+use memory_backend::reject::handle_rejection;
+use memory_backend::routes;
+use serde_json::Value;
+use warp::http::{Request, Response, StatusCode};
+use warp::hyper::body::{Bytes, HttpBody};
+use warp::hyper::{Body, Client};
+use warp::test::{RequestBuilder, WsClient};
+use warp::Filter;
+
+pub const MASTER_KEY: &str = "test-master-key";
+
+pub struct TestApp {
+    pub store: Store,
+}
+
+impl TestApp {
+    /// A store without save file, timers or countdown, so every request
+    /// takes effect right away.
+    pub fn new() -> Self {
+        let store = MemoryStore {
+            master_key: MASTER_KEY.to_owned(),
+            defaults: GameConfig::default().reveal_ms(0),
+            ..Default::default()
+        };
+        TestApp {
+            store: Store::new(tokio::sync::RwLock::new(store)),
+        }
+    }
+
+    pub async fn send(&self, request: RequestBuilder) -> Response<Bytes> {
+        let api = routes::api(self.store.clone()).recover(handle_rejection);
+        request.reply(&api).await
+    }
+
+    pub async fn get(&self, path: &str, cookie: Option<(&str, &str)>) -> Response<Bytes> {
+        self.send(request("GET", path, cookie)).await
+    }
+
+    pub async fn post(&self, path: &str, cookie: Option<(&str, &str)>) -> Response<Bytes> {
+        self.send(request("POST", path, cookie)).await
+    }
+
+    /// Creates a game with the default board and returns its admin token.
+    pub async fn create_game(&self, id: &str) -> String {
+        let res = self
+            .post(
+                &format!("/create?id={}", id),
+                Some(("master_key", MASTER_KEY)),
+            )
+            .await;
+        assert_eq!(
+            res.status(),
+            StatusCode::OK,
+            "create failed: {:?}",
+            json(&res)
+        );
+        set_cookie(&res, &format!("admin_token_{}", id)).expect("no admin_token cookie")
+    }
+
+    /// Joins `name` to the game and returns the player's token.
+    pub async fn join(&self, id: &str, name: &str) -> String {
+        let res = self
+            .post(&format!("/join?id={}&name={}", id, name), None)
+            .await;
+        assert_eq!(
+            res.status(),
+            StatusCode::OK,
+            "join failed: {:?}",
+            json(&res)
+        );
+        set_cookie(&res, "memory_token").expect("no memory_token cookie")
+    }
+
+    /// Joins `player0` to `player{n-1}` and returns their tokens in order.
+    pub async fn join_players(&self, id: &str, n: usize) -> Vec<String> {
+        let mut tokens = Vec::with_capacity(n);
+        for i in 0..n {
+            tokens.push(self.join(id, &format!("player{}", i)).await);
+        }
+        tokens
+    }
+
+    /// Readies every player, so the last one starts the game.
+    pub async fn start(&self, id: &str, tokens: &[String]) {
+        for token in tokens {
+            let res = self
+                .post(&format!("/ready?id={}", id), Some(("memory_token", token)))
+                .await;
+            assert_eq!(
+                res.status(),
+                StatusCode::OK,
+                "ready failed: {:?}",
+                json(&res)
+            );
+        }
+    }
+
+    /// Posts `body` with `Transfer-Encoding: chunked` and no `Content-Length`,
+    /// which takes a real server, as `warp::test` always sets the length.
+    pub async fn post_chunked(
+        &self,
+        path: &str,
+        cookie: (&str, &str),
+        body: &'static str,
+    ) -> Response<Bytes> {
+        let api = routes::api(self.store.clone()).recover(handle_rejection);
+        let (addr, server) = warp::serve(api).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let chunks = futures_util::stream::iter([Ok::<_, std::io::Error>(body)]);
+        let request = Request::post(format!("http://{}{}", addr, path))
+            .header("cookie", format!("{}={}", cookie.0, cookie.1))
+            .body(Body::wrap_stream(chunks))
+            .unwrap();
+        let res = Client::new().request(request).await.unwrap();
+        let (parts, body) = res.into_parts();
+        let body = warp::hyper::body::to_bytes(body).await.unwrap();
+        Response::from_parts(parts, body)
+    }
+}
+
+pub fn request(method: &str, path: &str, cookie: Option<(&str, &str)>) -> RequestBuilder {
+    let request = warp::test::request().method(method).path(path);
+    match cookie {
+        Some((name, value)) => request.header("cookie", format!("{}={}", name, value)),
+        None => request,
+    }
+}
+
+pub fn json(res: &Response<Bytes>) -> Value {
+    serde_json::from_slice(res.body()).expect("body is not JSON")
+}
+
+/// The value the response sets for cookie `name`, if any.
+pub fn set_cookie(res: &Response<Bytes>, name: &str) -> Option<String> {
+    let prefix = format!("{}=", name);
+    res.headers()
+        .get_all("set-cookie")
+        .iter()
+        .filter_map(|header| header.to_str().ok())
+        .find_map(|header| {
+            let value = header.strip_prefix(&prefix)?;
+            Some(value.split(';').next().unwrap_or("").to_owned())
+        })
+}
+
+/// Reads the next event off an SSE body as its name and JSON data.
+pub async fn next_event(body: &mut Body) -> (String, Value) {
+    let chunk = body.data().await.expect("stream ended").unwrap();
+    let frame = std::str::from_utf8(&chunk).unwrap();
+    let name = frame
+        .lines()
+        .find_map(|line| line.strip_prefix("event:"))
+        .expect("no event name");
+    let data = frame
+        .lines()
+        .find_map(|line| line.strip_prefix("data:"))
+        .expect("no event data");
+    (name.to_owned(), serde_json::from_str(data).unwrap())
+}
+
+/// Reads the next message off a WebSocket as JSON.
+pub async fn next_message(client: &mut WsClient) -> Value {
+    let message = client.recv().await.expect("socket closed");
+    serde_json::from_str(message.to_str().expect("not a text message")).unwrap()
+}
@@ -0,0 +1,1182 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use memory_backend::deck::{malformed_urls, CardFace, MAX_IMAGE_URL_LENGTH};
+use memory_backend::memory::{
+    unix_timestamp, GameConfig, GameState, Memory, MemoryStore, RateLimit, Scoring, Store,
+    TurnMode, DEFAULT_COLUMNS, DEFAULT_MATCH_SIZE, DEFAULT_ROWS, MAX_EVENT_LOG, MIN_PLAYERS,
+    PALETTE, TIMED_BONUS_SECS,
+};
+use memory_backend::queries::{parse_layout, valid_id, valid_name, MAX_ID_LENGTH, MAX_NAME_LENGTH};
+use memory_backend::reject::{
+    InvalidBoardSize, InvalidCard, InvalidInput, NoPeeksLeft, NotEnoughImages, PeeksDisabled,
+    ResolvingMatch, UndoUsed,
+};
+use memory_backend::reply::{LeaderboardResponse, Players, RankingResponse};
+use memory_backend::sse_utils::{broadcast_sse, send_sse, GameEvent, SendSseError};
+
+fn new_game() -> Memory {
+    Memory::new("test".to_owned())
+}
+
+#[tokio::test]
+async fn starting_player_is_random() {
+    let mut starts: HashMap<String, usize> = HashMap::new();
+
+    for _ in 0..200 {
+        let mut game = new_game();
+        for name in ["alice", "bob", "carol"] {
+            game.add_new_player(name.to_owned(), None).unwrap();
+        }
+        game.start().await;
+
+        let starter = game.players.values().find(|p| p.turn).unwrap();
+        *starts.entry(starter.name.clone()).or_default() += 1;
+    }
+
+    assert_eq!(starts.len(), 3);
+}
+
+#[test]
+fn single_winner_has_the_most_points() {
+    let mut game = new_game();
+    for name in ["alice", "bob", "carol"] {
+        game.add_new_player(name.to_owned(), None).unwrap();
+    }
+    for player in game.players.values_mut() {
+        player.points = match player.name.as_str() {
+            "alice" => 3,
+            "bob" => 7,
+            _ => 5,
+        };
+    }
+
+    assert_eq!(game.winners(), vec!["bob"]);
+    let names: Vec<_> = game.standings().into_iter().map(|p| p.0).collect();
+    assert_eq!(names, vec!["bob", "carol", "alice"]);
+}
+
+#[test]
+fn tied_players_all_win() {
+    let mut game = new_game();
+    for name in ["alice", "bob", "carol"] {
+        game.add_new_player(name.to_owned(), None).unwrap();
+    }
+    for player in game.players.values_mut() {
+        player.points = if player.name == "alice" { 2 } else { 6 };
+    }
+
+    assert_eq!(game.winners(), vec!["bob", "carol"]);
+}
+
+#[tokio::test]
+async fn mismatch_passes_the_turn_and_match_hides_cards() {
+    let mut game = new_game();
+    let tokens: Vec<String> = ["alice", "bob"]
+        .iter()
+        .map(|name| game.add_new_player(name.to_string(), None).unwrap())
+        .collect();
+    game.start().await;
+
+    let first = tokens
+        .iter()
+        .find(|t| game.players[*t].turn)
+        .unwrap()
+        .clone();
+    let second = tokens.iter().find(|t| **t != first).unwrap().clone();
+
+    let a = 0;
+    let b = (1..game.cards.len())
+        .find(|i| game.cards[*i].img_path != game.cards[a].img_path)
+        .unwrap();
+    game.pick_card(a, first.clone()).await.unwrap();
+    game.pick_card(b, first.clone()).await.unwrap();
+
+    assert!(game.has_pending_mismatch());
+    assert!(game.cards[a].flipped && game.cards[b].flipped);
+    assert!(!game.players[&first].turn);
+
+    game.skip_turn().await;
+
+    assert!(!game.cards.iter().any(|c| c.flipped));
+    assert!(game.players[&second].turn);
+
+    let c = (1..game.cards.len())
+        .find(|i| game.cards[*i].img_path == game.cards[a].img_path)
+        .unwrap();
+    game.pick_card(a, second.clone()).await.unwrap();
+    game.pick_card(c, second.clone()).await.unwrap();
+
+    assert!(game.cards[a].gone && game.cards[c].gone);
+    assert!(!game.cards.iter().any(|card| card.flipped));
+    assert_eq!(game.players[&second].points, 1);
+    assert!(game.players[&second].turn);
+}
+
+#[tokio::test]
+async fn third_flip_is_rejected() {
+    let mut game = new_game();
+    let token = game.add_new_player("alice".to_owned(), None).unwrap();
+    game.add_new_player("bob".to_owned(), None).unwrap();
+    game.start().await;
+
+    let b = (1..game.cards.len())
+        .find(|i| game.cards[*i].img_path != game.cards[0].img_path)
+        .unwrap();
+    let c = (1..game.cards.len()).find(|i| *i != b).unwrap();
+    game.pick_card(0, token.clone()).await.unwrap();
+    game.pick_card(b, token.clone()).await.unwrap();
+
+    let Err(err) = game.pick_card(c, token).await else {
+        panic!("third flip was accepted");
+    };
+    assert!(err.find::<ResolvingMatch>().is_some());
+    assert!(!game.cards[c].flipped);
+
+    // Hiding the mismatch unlocks the board for the next player.
+    game.skip_turn().await;
+    let next = game.players.iter().find(|(_, p)| p.turn).unwrap().0.clone();
+    game.pick_card(c, next).await.unwrap();
+}
+
+#[tokio::test]
+async fn broadcast_skips_and_drops_closed_channels() {
+    let mut game = new_game();
+    let alice = game.add_new_player("alice".to_owned(), None).unwrap();
+    let bob = game.add_new_player("bob".to_owned(), None).unwrap();
+
+    let (alice_sender, alice_receiver) = tokio::sync::mpsc::channel(2);
+    let (bob_sender, mut bob_receiver) = tokio::sync::mpsc::channel(2);
+    game.players
+        .get_mut(&alice)
+        .unwrap()
+        .add_sender(alice_sender);
+    game.players.get_mut(&bob).unwrap().add_sender(bob_sender);
+    drop(alice_receiver);
+
+    game.broadcast("chat", "hello").await;
+
+    let event = bob_receiver.recv().await.unwrap();
+    assert_eq!(event.name, "chat");
+    assert!(!game.players[&alice].is_connected());
+    assert!(game.players[&bob].is_connected());
+}
+
+#[tokio::test]
+async fn every_tab_of_a_player_gets_broadcasts() {
+    let mut game = new_game();
+    let alice = game.add_new_player("alice".to_owned(), None).unwrap();
+
+    let (first_sender, mut first_receiver) = tokio::sync::mpsc::channel(2);
+    let (second_sender, mut second_receiver) = tokio::sync::mpsc::channel(2);
+    let player = game.players.get_mut(&alice).unwrap();
+    player.add_sender(first_sender.clone());
+    player.add_sender(second_sender);
+
+    game.broadcast("chat", "hello").await;
+    assert_eq!(first_receiver.recv().await.unwrap().name, "chat");
+    assert_eq!(second_receiver.recv().await.unwrap().name, "chat");
+
+    // Closing one tab keeps the player connected through the other.
+    drop(second_receiver);
+    game.broadcast("chat", "again").await;
+    assert_eq!(first_receiver.recv().await.unwrap().name, "chat");
+    assert_eq!(game.players[&alice].senders.len(), 1);
+    assert!(game.disconnected_players(Duration::ZERO).is_empty());
+
+    game.drop_channel(&first_sender);
+    assert!(!game.players[&alice].is_connected());
+    assert_eq!(game.disconnected_players(Duration::ZERO), vec![alice]);
+}
+
+#[tokio::test]
+async fn gone_card_cannot_be_picked_again() {
+    let mut game = new_game();
+    let token = game.add_new_player("alice".to_owned(), None).unwrap();
+    game.add_new_player("bob".to_owned(), None).unwrap();
+    game.start().await;
+    let token = if game.players[&token].turn {
+        token
+    } else {
+        game.players.iter().find(|(_, p)| p.turn).unwrap().0.clone()
+    };
+
+    let b = (1..game.cards.len())
+        .find(|i| game.cards[*i].img_path == game.cards[0].img_path)
+        .unwrap();
+    game.pick_card(0, token.clone()).await.unwrap();
+    game.pick_card(b, token.clone()).await.unwrap();
+    assert!(game.cards[0].gone);
+
+    let Err(err) = game.pick_card(0, token).await else {
+        panic!("gone card was accepted");
+    };
+    assert!(err.find::<InvalidCard>().is_some());
+    assert!(!game.cards[0].flipped);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn concurrent_picks_of_the_same_card_flip_it_once() {
+    let mut game = new_game();
+    game.add_new_player("alice".to_owned(), None).unwrap();
+    game.add_new_player("bob".to_owned(), None).unwrap();
+    game.start().await;
+    let token = game.players.iter().find(|(_, p)| p.turn).unwrap().0.clone();
+
+    let mut store = MemoryStore::default();
+    store.games.insert(game.id.clone(), game);
+    let store = Store::new(tokio::sync::RwLock::new(store));
+
+    let picks: Vec<_> = (0..2)
+        .map(|_| {
+            let store = store.clone();
+            let token = token.clone();
+            tokio::spawn(async move {
+                let mut lock = store.write().await;
+                let game = lock.get_game_mut("test").unwrap();
+                game.pick_card(0, token).await.is_ok()
+            })
+        })
+        .collect();
+
+    let mut succeeded = 0;
+    for pick in picks {
+        if pick.await.unwrap() {
+            succeeded += 1;
+        }
+    }
+
+    assert_eq!(succeeded, 1);
+    let lock = store.read().await;
+    assert_eq!(lock.get_game("test").unwrap().history.len(), 1);
+}
+
+#[test]
+fn same_seed_deals_the_same_board() {
+    let deal = |seed, rematches| {
+        let mut game =
+            Memory::with_config("test".to_owned(), GameConfig::default().seed(seed)).unwrap();
+        for _ in 0..rematches {
+            game.reset();
+        }
+        game.cards
+            .into_iter()
+            .map(|card| card.img_path)
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(deal(42, 0), deal(42, 0));
+    assert_ne!(deal(42, 0), deal(43, 0));
+    // Rematches of a seeded game are dealt the same way too.
+    assert_eq!(deal(42, 1), deal(42, 1));
+    assert_ne!(deal(42, 1), deal(42, 0));
+}
+
+#[test]
+fn duplicate_name_is_rejected() {
+    let mut game = new_game();
+    game.add_new_player("alice".to_owned(), None).unwrap();
+
+    assert!(game.add_new_player("alice".to_owned(), None).is_err());
+    assert_eq!(game.players.len(), 1);
+}
+
+#[tokio::test]
+async fn consecutive_matches_earn_a_streak_bonus() {
+    let mut game = new_game();
+    game.scoring = Scoring::Streak;
+    game.streak_bonus = 2;
+    game.add_new_player("alice".to_owned(), None).unwrap();
+    game.add_new_player("bob".to_owned(), None).unwrap();
+    game.start().await;
+    let token = game.players.iter().find(|(_, p)| p.turn).unwrap().0.clone();
+
+    for _ in 0..2 {
+        let a = game.cards.iter().position(|c| !c.gone).unwrap();
+        let b = (a + 1..game.cards.len())
+            .find(|i| game.cards[*i].img_path == game.cards[a].img_path)
+            .unwrap();
+        game.pick_card(a, token.clone()).await.unwrap();
+        game.pick_card(b, token.clone()).await.unwrap();
+    }
+
+    assert_eq!(game.players[&token].points, 1 + 3);
+    assert_eq!(game.players[&token].current_streak, 2);
+    assert_eq!(game.standings()[0].4, 2);
+}
+
+#[test]
+fn ranking_shares_ranks_between_tied_players() {
+    let mut game = new_game();
+    for name in ["alice", "bob", "carol", "dave"] {
+        game.add_new_player(name.to_owned(), None).unwrap();
+    }
+    for player in game.players.values_mut() {
+        player.points = match player.name.as_str() {
+            "alice" => 5,
+            "bob" => 5,
+            "carol" => 3,
+            _ => 1,
+        };
+    }
+
+    let ranking = RankingResponse::from(game.standings(), Some(3));
+    let ranks: Vec<_> = ranking
+        .players
+        .iter()
+        .map(|p| (p.name.as_str(), p.rank))
+        .collect();
+    assert_eq!(ranks, vec![("alice", 1), ("bob", 1), ("carol", 3)]);
+}
+
+#[test]
+fn leaderboard_order_is_stable() {
+    let mut game = new_game();
+    for name in ["dave", "alice", "carol", "bob"] {
+        game.add_new_player(name.to_owned(), None).unwrap();
+    }
+    for player in game.players.values_mut() {
+        player.points = if player.name == "carol" { 4 } else { 2 };
+    }
+
+    let names = |players: Players| players.into_iter().map(|p| p.0).collect::<Vec<_>>();
+    let expected = vec!["carol", "alice", "bob", "dave"];
+    for _ in 0..10 {
+        let live = LeaderboardResponse::from(&game.players.values().collect());
+        assert_eq!(names(live.players), expected);
+        assert_eq!(names(game.get_state(None).players), expected);
+    }
+}
+
+#[test]
+fn players_get_distinct_colors_and_may_pick_one() {
+    let mut game = new_game();
+    let alice = game.add_new_player("alice".to_owned(), None).unwrap();
+    let bob = game
+        .add_new_player("bob".to_owned(), Some(PALETTE[3]))
+        .unwrap();
+    let carol = game
+        .add_new_player("carol".to_owned(), Some(PALETTE[3]))
+        .unwrap();
+
+    assert_eq!(game.players[&alice].color, PALETTE[0]);
+    assert_eq!(game.players[&bob].color, PALETTE[3]);
+    assert_eq!(game.players[&carol].color, PALETTE[1]);
+}
+
+#[test]
+fn a_single_player_cannot_start_a_regular_game() {
+    let mut game = new_game();
+    game.add_new_player("alice".to_owned(), None).unwrap();
+    assert!(!game.has_enough_players());
+
+    game.add_new_player("bob".to_owned(), None).unwrap();
+    assert!(game.has_enough_players());
+}
+
+#[tokio::test]
+async fn practice_game_hands_the_turn_back_to_its_only_player() {
+    let mut game = new_game();
+    game.practice = true;
+    let token = game.add_new_player("alice".to_owned(), None).unwrap();
+    assert!(game.has_enough_players());
+    game.start().await;
+
+    let b = (1..game.cards.len())
+        .find(|i| game.cards[*i].img_path != game.cards[0].img_path)
+        .unwrap();
+    game.pick_card(0, token.clone()).await.unwrap();
+    game.pick_card(b, token.clone()).await.unwrap();
+    assert!(game.has_pending_mismatch());
+
+    game.skip_turn().await;
+
+    assert!(game.players[&token].turn);
+    assert_eq!(game.current_player(), Some("alice"));
+}
+
+#[tokio::test]
+async fn game_over_broadcast_carries_every_final_score() {
+    let mut game = new_game();
+    let alice = game.add_new_player("alice".to_owned(), None).unwrap();
+    game.add_new_player("bob".to_owned(), None).unwrap();
+    let (sender, mut receiver) = tokio::sync::mpsc::channel(256);
+    game.players.get_mut(&alice).unwrap().add_sender(sender);
+    game.start().await;
+    let token = game.players.iter().find(|(_, p)| p.turn).unwrap().0.clone();
+
+    while let Some(a) = game.cards.iter().position(|c| !c.gone) {
+        let b = (a + 1..game.cards.len())
+            .find(|i| game.cards[*i].img_path == game.cards[a].img_path)
+            .unwrap();
+        game.pick_card(a, token.clone()).await.unwrap();
+        game.pick_card(b, token.clone()).await.unwrap();
+    }
+
+    let mut game_over = None;
+    while let Ok(event) = receiver.try_recv() {
+        if event.name == "gameOver" {
+            game_over = Some(event.data);
+        }
+    }
+    let data = game_over.expect("no gameOver event");
+    let scores: Vec<(String, u64)> = data["leaderboard"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|p| (p[0].as_str().unwrap().to_owned(), p[1].as_u64().unwrap()))
+        .collect();
+    let pairs = (game.cards.len() / DEFAULT_MATCH_SIZE) as u64;
+    let winner = game.players[&token].name.clone();
+    let loser = if winner == "alice" { "bob" } else { "alice" };
+    assert_eq!(scores, vec![(winner, pairs), (loser.to_owned(), 0)]);
+}
+
+#[test]
+fn names_and_ids_are_validated() {
+    assert!(valid_name("Alice B_2"));
+    assert!(!valid_name(""));
+    assert!(!valid_name(" alice"));
+    assert!(!valid_name("alice<script>"));
+    assert!(!valid_name(&"a".repeat(MAX_NAME_LENGTH + 1)));
+
+    assert!(valid_id("room-1_a"));
+    assert!(!valid_id("room 1"));
+    assert!(!valid_id("räum"));
+    assert!(!valid_id(&"a".repeat(MAX_ID_LENGTH + 1)));
+}
+
+#[tokio::test]
+async fn state_counts_remaining_cards() {
+    let mut game = new_game();
+    let token = game.add_new_player("alice".to_owned(), None).unwrap();
+    game.practice = true;
+    game.start().await;
+
+    let b = (1..game.cards.len())
+        .find(|i| game.cards[*i].img_path == game.cards[0].img_path)
+        .unwrap();
+    game.pick_card(0, token.clone()).await.unwrap();
+    game.pick_card(b, token).await.unwrap();
+
+    let state = game.get_state(None);
+    assert_eq!(state.total, DEFAULT_COLUMNS * DEFAULT_ROWS);
+    assert_eq!(state.remaining, state.total - 2);
+    assert_eq!(state.remaining + state.hidden.len(), state.total);
+}
+
+async fn points_after_mismatch(penalty: usize, points: usize) -> usize {
+    let mut game = new_game();
+    game.mismatch_penalty = penalty;
+    game.add_new_player("alice".to_owned(), None).unwrap();
+    game.add_new_player("bob".to_owned(), None).unwrap();
+    game.start().await;
+    let token = game.players.iter().find(|(_, p)| p.turn).unwrap().0.clone();
+    game.players.get_mut(&token).unwrap().points = points;
+
+    let b = (1..game.cards.len())
+        .find(|i| game.cards[*i].img_path != game.cards[0].img_path)
+        .unwrap();
+    game.pick_card(0, token.clone()).await.unwrap();
+    game.pick_card(b, token.clone()).await.unwrap();
+    game.players[&token].points
+}
+
+#[tokio::test]
+async fn mismatch_penalty_is_subtracted_and_saturates() {
+    assert_eq!(points_after_mismatch(0, 3).await, 3);
+    assert_eq!(points_after_mismatch(2, 3).await, 1);
+    assert_eq!(points_after_mismatch(5, 3).await, 0);
+}
+
+#[test]
+fn irregular_layouts_are_carried_and_validated() {
+    let layout = parse_layout("4, 4,2,4,4").unwrap();
+    assert_eq!(layout, vec![4, 4, 2, 4, 4]);
+    assert_eq!(parse_layout("4,,4"), None);
+    assert_eq!(parse_layout("4,0,4"), None);
+    assert_eq!(parse_layout("four"), None);
+
+    let game = Memory::with_config(
+        "test".to_owned(),
+        GameConfig::default().layout(layout.clone()),
+    )
+    .unwrap();
+    assert_eq!(game.size, 18);
+    assert_eq!(game.cards.len(), 18);
+    assert_eq!((game.columns, game.rows), (4, 5));
+
+    let state = game.get_state(None);
+    assert_eq!(state.layout, Some(layout));
+    assert_eq!(state.total, 18);
+
+    let Err(odd) = Memory::with_config("odd".to_owned(), GameConfig::default().layout(vec![3, 4]))
+    else {
+        panic!("odd layout was accepted");
+    };
+    assert!(odd.find::<InvalidBoardSize>().is_some());
+    let Err(too_big) = Memory::with_config(
+        "big".to_owned(),
+        GameConfig::default().layout(vec![100, 100]),
+    ) else {
+        panic!("layout larger than the image set was accepted");
+    };
+    assert!(too_big.find::<NotEnoughImages>().is_some());
+}
+
+#[test]
+fn boards_need_a_distinct_image_per_set() {
+    // The default board uses every built-in image exactly once.
+    let game = new_game();
+    assert_eq!(game.images.len(), DEFAULT_COLUMNS * DEFAULT_ROWS / 2);
+
+    let Err(err) = Memory::with_config("big".to_owned(), GameConfig::default().board(8, 8)) else {
+        panic!("board larger than the image set was accepted");
+    };
+    let missing = err.find::<NotEnoughImages>().unwrap();
+    assert_eq!((missing.needed, missing.supplied), (32, 27));
+
+    // Duplicate faces only count once.
+    let images = vec!["a".to_owned(), "a".to_owned(), "b".to_owned()];
+    let config = GameConfig::default().board(2, 2).images(images);
+    assert!(Memory::with_config("dupes".to_owned(), config).is_ok());
+    let images = vec!["a".to_owned(), "a".to_owned()];
+    let config = GameConfig::default().board(2, 2).images(images);
+    let Err(err) = Memory::with_config("dupes".to_owned(), config) else {
+        panic!("duplicate images were counted twice");
+    };
+    assert_eq!(err.find::<NotEnoughImages>().unwrap().supplied, 1);
+}
+
+#[test]
+fn games_must_admit_enough_players_to_start() {
+    let config = GameConfig::default().max_players(MIN_PLAYERS - 1);
+    let Err(err) = Memory::with_config("small".to_owned(), config) else {
+        panic!("max_players below the minimum was accepted");
+    };
+    assert!(err.find::<InvalidInput>().is_some());
+
+    let config = GameConfig::default().max_players(MIN_PLAYERS);
+    let game = Memory::with_config("small".to_owned(), config).unwrap();
+    assert_eq!(game.max_players, MIN_PLAYERS);
+}
+
+#[tokio::test]
+async fn countdown_locks_the_lobby_until_started_or_reset() {
+    let mut game = new_game();
+    game.add_new_player("alice".to_owned(), None).unwrap();
+    game.add_new_player("bob".to_owned(), None).unwrap();
+
+    let turn = game.begin_countdown();
+    assert!(matches!(game.state, GameState::Starting));
+    assert_eq!(game.current_player(), None);
+
+    game.reset();
+    assert!(matches!(game.state, GameState::Lobby));
+    assert_ne!(game.turn_number(), turn);
+
+    game.begin_countdown();
+    game.abort_countdown();
+    assert!(matches!(game.state, GameState::Lobby));
+}
+
+#[tokio::test]
+async fn closed_streams_start_the_disconnect_grace_period() {
+    let mut game = new_game();
+    let alice = game.add_new_player("alice".to_owned(), None).unwrap();
+    let bob = game.add_new_player("bob".to_owned(), None).unwrap();
+
+    let (alice_sender, alice_receiver) = tokio::sync::mpsc::channel(2);
+    let (bob_sender, _bob_receiver) = tokio::sync::mpsc::channel(2);
+    game.players
+        .get_mut(&alice)
+        .unwrap()
+        .add_sender(alice_sender);
+    game.players.get_mut(&bob).unwrap().add_sender(bob_sender);
+    assert!(game.disconnected_players(Duration::ZERO).is_empty());
+
+    drop(alice_receiver);
+    game.broadcast("chat", "hello").await;
+
+    assert_eq!(game.disconnected_players(Duration::ZERO), vec![alice]);
+    assert!(game
+        .disconnected_players(Duration::from_secs(3600))
+        .is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn simultaneous_final_readies_start_the_game_once() {
+    let mut game = new_game();
+    let alice = game.add_new_player("alice".to_owned(), None).unwrap();
+    let bob = game.add_new_player("bob".to_owned(), None).unwrap();
+
+    let mut store = MemoryStore::default();
+    store.games.insert(game.id.clone(), game);
+    let store = Store::new(tokio::sync::RwLock::new(store));
+
+    let readies: Vec<_> = [alice.clone(), bob, alice]
+        .into_iter()
+        .map(|token| {
+            let store = store.clone();
+            tokio::spawn(async move {
+                let mut lock = store.write().await;
+                let game = lock.get_game_mut("test").unwrap();
+                match game.mark_ready(&token) {
+                    Ok(true) => {
+                        game.start().await;
+                        true
+                    }
+                    _ => false,
+                }
+            })
+        })
+        .collect();
+
+    let mut started = 0;
+    for ready in readies {
+        if ready.await.unwrap() {
+            started += 1;
+        }
+    }
+
+    assert_eq!(started, 1);
+    let lock = store.read().await;
+    let game = lock.get_game("test").unwrap();
+    assert!(matches!(game.state, GameState::Running));
+    assert_eq!(game.players.values().filter(|p| p.turn).count(), 1);
+}
+
+#[tokio::test]
+async fn unserializable_events_are_not_sent() {
+    let (sender, mut receiver) = tokio::sync::mpsc::channel(2);
+    let reply: HashMap<(u8, u8), u8> = HashMap::from([((0, 1), 2)]);
+
+    let result = send_sse("broken", &reply, Some(&sender)).await;
+
+    assert!(matches!(result, Err(SendSseError::Serialize(_))));
+    assert!(receiver.try_recv().is_err());
+    let closed = broadcast_sse("broken", &reply, vec![&sender]).await;
+    assert!(closed.is_empty());
+    assert!(receiver.try_recv().is_err());
+}
+
+#[test]
+fn state_reports_own_standing_with_shared_ranks() {
+    let mut game = new_game();
+    let alice = game.add_new_player("alice".to_owned(), None).unwrap();
+    let bob = game.add_new_player("bob".to_owned(), None).unwrap();
+    let carol = game.add_new_player("carol".to_owned(), None).unwrap();
+    game.players.get_mut(&alice).unwrap().points = 3;
+    game.players.get_mut(&bob).unwrap().points = 3;
+    game.players.get_mut(&carol).unwrap().points = 1;
+    game.players.get_mut(&carol).unwrap().turn = true;
+
+    let state = game.get_state(Some(&alice));
+    assert_eq!((state.points, state.rank, state.turn), (3, Some(1), false));
+    assert_eq!(game.get_state(Some(&bob)).rank, Some(1));
+    let state = game.get_state(Some(&carol));
+    assert_eq!((state.points, state.rank, state.turn), (1, Some(3), true));
+
+    let state = game.get_state(None);
+    assert_eq!((state.points, state.rank, state.turn), (0, None, false));
+}
+
+/// Lets the player holding `token` find the first pair still on the board.
+async fn find_pair(game: &mut Memory, token: &str) {
+    let a = game.cards.iter().position(|c| !c.gone).unwrap();
+    let b = (a + 1..game.cards.len())
+        .find(|i| game.cards[*i].img_path == game.cards[a].img_path)
+        .unwrap();
+    game.pick_card(a, token.to_owned()).await.unwrap();
+    game.pick_card(b, token.to_owned()).await.unwrap();
+}
+
+async fn start_two_player_game(game: &mut Memory) -> String {
+    game.add_new_player("alice".to_owned(), None).unwrap();
+    game.add_new_player("bob".to_owned(), None).unwrap();
+    game.start().await;
+    game.players.iter().find(|(_, p)| p.turn).unwrap().0.clone()
+}
+
+#[tokio::test]
+async fn reaching_the_target_ends_the_game_early() {
+    let mut game = new_game();
+    game.target_matches = Some(2);
+    let (sender, mut receiver) = tokio::sync::mpsc::channel(256);
+    game.add_spectator(sender);
+    let token = start_two_player_game(&mut game).await;
+
+    find_pair(&mut game, &token).await;
+    assert!(matches!(game.state, GameState::Running));
+    find_pair(&mut game, &token).await;
+
+    assert!(matches!(game.state, GameState::Finished));
+    assert_eq!(game.cards.iter().filter(|c| c.gone).count(), 4);
+    let mut winners = None;
+    while let Ok(event) = receiver.try_recv() {
+        if event.name == "gameOver" {
+            winners = Some(event.data["winners"].clone());
+        }
+    }
+    let winner = game.players[&token].name.clone();
+    assert_eq!(
+        winners.expect("no gameOver event"),
+        serde_json::json!([winner])
+    );
+}
+
+#[tokio::test]
+async fn clearing_the_board_ends_the_game_below_the_target() {
+    let mut game = new_game();
+    let pairs = game.cards.len() / DEFAULT_MATCH_SIZE;
+    game.target_matches = Some(pairs + 1);
+    let token = start_two_player_game(&mut game).await;
+
+    for _ in 0..pairs - 1 {
+        find_pair(&mut game, &token).await;
+    }
+    assert!(matches!(game.state, GameState::Running));
+    find_pair(&mut game, &token).await;
+
+    assert!(matches!(game.state, GameState::Finished));
+    assert!(game.cards.iter().all(|c| c.gone));
+}
+
+#[test]
+fn peeks_are_limited_per_player_and_game() {
+    let mut game = new_game();
+    let alice = game.add_new_player("alice".to_owned(), None).unwrap();
+    let Err(err) = game.peek(&alice) else {
+        panic!("peeked without peeks enabled");
+    };
+    assert!(err.find::<PeeksDisabled>().is_some());
+
+    game.peeks = 1;
+    let bob = game.add_new_player("bob".to_owned(), None).unwrap();
+    let faces = game.peek(&bob).unwrap();
+    let expected: Vec<_> = game
+        .cards
+        .iter()
+        .map(|c| CardFace::new(&c.img_path))
+        .collect();
+    assert_eq!(faces, expected);
+    let Err(err) = game.peek(&bob) else {
+        panic!("peeked more often than allowed");
+    };
+    assert!(err.find::<NoPeeksLeft>().is_some());
+
+    game.reset();
+    assert_eq!(game.players[&alice].peeks_left, 1);
+    assert_eq!(game.players[&bob].peeks_left, 1);
+}
+
+#[tokio::test]
+async fn last_scorer_is_kept_until_the_turn_passes() {
+    let mut game = new_game();
+    let token = start_two_player_game(&mut game).await;
+    let name = game.players[&token].name.clone();
+    assert_eq!(game.last_scorer, None);
+
+    find_pair(&mut game, &token).await;
+    assert_eq!(game.last_scorer, Some(name.clone()));
+
+    let a = game.cards.iter().position(|c| !c.gone).unwrap();
+    let b = (a + 1..game.cards.len())
+        .find(|i| !game.cards[*i].gone && game.cards[*i].img_path != game.cards[a].img_path)
+        .unwrap();
+    game.pick_card(a, token.clone()).await.unwrap();
+    assert_eq!(game.last_scorer, Some(name));
+    game.pick_card(b, token).await.unwrap();
+    game.skip_turn().await;
+    assert_eq!(game.last_scorer, None);
+}
+
+#[test]
+fn only_http_urls_make_a_deck() {
+    let long = format!("https://example.com/{}", "a".repeat(MAX_IMAGE_URL_LENGTH));
+    let images: Vec<String> = [
+        "https://example.com/a.png",
+        "http://example.com/b.jpg",
+        "ftp://example.com/c.png",
+        "javascript:alert(1)",
+        "/img/d.png",
+        &long,
+    ]
+    .iter()
+    .map(|url| url.to_string())
+    .collect();
+
+    assert_eq!(malformed_urls(&images), images[2..].to_vec());
+}
+
+/// Join indices of the players holding the turn over `turns` skipped turns
+/// of a seeded four-player game.
+async fn turn_sequence(mode: TurnMode, turns: usize) -> Vec<usize> {
+    let config = GameConfig::default().seed(7).turn_mode(mode);
+    let mut game = Memory::with_config("test".to_owned(), config).unwrap();
+    for i in 0..4 {
+        game.add_new_player(format!("p{}", i), None).unwrap();
+    }
+    game.start().await;
+
+    let mut sequence = Vec::new();
+    for _ in 0..turns {
+        let name = game.current_player().unwrap();
+        sequence.push(name[1..].parse().unwrap());
+        game.skip_turn().await;
+    }
+    sequence
+}
+
+#[tokio::test]
+async fn sequential_turns_follow_join_order() {
+    let sequence = turn_sequence(TurnMode::Sequential, 8).await;
+    assert_eq!(sequence, turn_sequence(TurnMode::Sequential, 8).await);
+    for pair in sequence.windows(2) {
+        assert_eq!(pair[1], (pair[0] + 1) % 4);
+    }
+}
+
+#[tokio::test]
+async fn reverse_turns_go_backwards() {
+    let sequence = turn_sequence(TurnMode::Reverse, 8).await;
+    assert_eq!(sequence, turn_sequence(TurnMode::Reverse, 8).await);
+    for pair in sequence.windows(2) {
+        assert_eq!(pair[1], (pair[0] + 3) % 4);
+    }
+}
+
+#[tokio::test]
+async fn random_turns_are_reproducible_with_a_seed() {
+    let sequence = turn_sequence(TurnMode::Random, 12).await;
+    assert_eq!(sequence, turn_sequence(TurnMode::Random, 12).await);
+    for pair in sequence.windows(2) {
+        assert_ne!(pair[0], pair[1]);
+    }
+    assert!(sequence.windows(2).any(|pair| pair[1] != (pair[0] + 1) % 4));
+    assert!((0..4).all(|player| sequence.contains(&player)));
+}
+
+#[tokio::test]
+async fn reverse_turns_skip_a_removed_player() {
+    let mut game = new_game();
+    game.turn_mode = TurnMode::Reverse;
+    let tokens: Vec<String> = (0..3)
+        .map(|i| game.add_new_player(format!("p{}", i), None).unwrap())
+        .collect();
+    game.start().await;
+    while game.current_player() != Some("p2") {
+        game.skip_turn().await;
+    }
+
+    game.remove_player(&tokens[2]).await;
+    assert_eq!(game.current_player(), Some("p1"));
+    game.skip_turn().await;
+    assert_eq!(game.current_player(), Some("p0"));
+}
+
+#[tokio::test]
+async fn leaving_mid_turn_turns_the_players_cards_back() {
+    let mut game = new_game();
+    let token = start_two_player_game(&mut game).await;
+    let other = game.players.keys().find(|t| **t != token).unwrap().clone();
+    let (sender, mut receiver) = tokio::sync::mpsc::channel(16);
+    game.players.get_mut(&other).unwrap().add_sender(sender);
+    game.pick_card(0, token.clone()).await.unwrap();
+    while receiver.try_recv().is_ok() {}
+
+    game.remove_player(&token).await;
+    assert!(!game.cards[0].flipped);
+    let events: Vec<GameEvent> = std::iter::from_fn(|| receiver.try_recv().ok()).collect();
+    assert_eq!(events[0].name, "unflipCard");
+    assert_eq!(events[0].data["card_id"], 0);
+    assert_eq!(events[1].name, "turn");
+}
+
+#[tokio::test]
+async fn the_last_player_leaving_resets_the_game() {
+    let mut game = new_game();
+    let token = start_two_player_game(&mut game).await;
+    find_pair(&mut game, &token).await;
+    let other = game.players.keys().find(|t| **t != token).unwrap().clone();
+
+    game.remove_player(&other).await;
+    game.remove_player(&token).await;
+    assert!(matches!(game.state, GameState::Lobby));
+    assert!(game.cards.iter().all(|card| !card.gone && !card.flipped));
+    assert!(game.history.is_empty());
+}
+
+#[tokio::test]
+async fn each_turn_allows_one_undo() {
+    let mut game = new_game();
+    let token = start_two_player_game(&mut game).await;
+    game.pick_card(0, token.clone()).await.unwrap();
+    assert_eq!(game.unflip_last().await.unwrap(), 0);
+    game.pick_card(1, token).await.unwrap();
+
+    let Err(err) = game.unflip_last().await else {
+        panic!("a second undo was accepted");
+    };
+    assert!(err.find::<UndoUsed>().is_some());
+    assert!(game.cards[1].flipped);
+
+    game.skip_turn().await;
+    let next = game.players.iter().find(|(_, p)| p.turn).unwrap().0.clone();
+    game.pick_card(0, next).await.unwrap();
+    assert_eq!(game.unflip_last().await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn event_log_replays_recent_broadcasts_only() {
+    let mut game = new_game();
+    for i in 0..3 {
+        game.broadcast("chat", i).await;
+    }
+
+    let missed = game.events_since(1).unwrap();
+    let ids: Vec<Option<u64>> = missed.iter().map(|event| event.id).collect();
+    assert_eq!(ids, vec![Some(2), Some(3)]);
+    assert_eq!(missed[0].data, 1);
+    assert!(game.events_since(3).unwrap().is_empty());
+    assert!(game.events_since(4).is_none());
+
+    for i in 0..MAX_EVENT_LOG {
+        game.broadcast("chat", i).await;
+    }
+    assert!(game.events_since(2).is_none());
+    assert_eq!(game.events_since(3).unwrap().len(), MAX_EVENT_LOG);
+}
+
+async fn score_two_matches(scoring: Scoring) -> usize {
+    let mut game = new_game();
+    game.scoring = scoring;
+    game.streak_bonus = 2;
+    game.add_new_player("alice".to_owned(), None).unwrap();
+    game.add_new_player("bob".to_owned(), None).unwrap();
+    game.start().await;
+    let token = game.players.iter().find(|(_, p)| p.turn).unwrap().0.clone();
+
+    for _ in 0..2 {
+        let a = game.cards.iter().position(|c| !c.gone).unwrap();
+        let b = (a + 1..game.cards.len())
+            .find(|i| game.cards[*i].img_path == game.cards[a].img_path)
+            .unwrap();
+        game.pick_card(a, token.clone()).await.unwrap();
+        game.pick_card(b, token.clone()).await.unwrap();
+    }
+    game.players[&token].points
+}
+
+#[tokio::test]
+async fn classic_scoring_ignores_streaks() {
+    assert_eq!(score_two_matches(Scoring::Classic).await, 2);
+}
+
+#[tokio::test]
+async fn timed_scoring_rewards_fast_matches() {
+    assert_eq!(
+        score_two_matches(Scoring::TimedBonus).await,
+        2 * (1 + TIMED_BONUS_SECS as usize)
+    );
+}
+
+#[test]
+fn timed_bonus_shrinks_every_second() {
+    let points = |secs| Scoring::TimedBonus.points(0, 0, Duration::from_secs(secs));
+    assert_eq!(points(0), 1 + TIMED_BONUS_SECS as usize);
+    assert_eq!(points(2), TIMED_BONUS_SECS as usize - 1);
+    assert_eq!(points(TIMED_BONUS_SECS), 1);
+    assert_eq!(points(60), 1);
+}
+
+#[tokio::test]
+async fn the_final_board_is_sent_once_when_the_game_ends() {
+    let mut game = new_game();
+    let alice = game.add_new_player("alice".to_owned(), None).unwrap();
+    game.add_new_player("bob".to_owned(), None).unwrap();
+    let (sender, mut receiver) = tokio::sync::mpsc::channel(256);
+    game.players.get_mut(&alice).unwrap().add_sender(sender);
+    game.start().await;
+    let token = game.players.iter().find(|(_, p)| p.turn).unwrap().0.clone();
+
+    let mut boards = Vec::new();
+    while let Some(a) = game.cards.iter().position(|c| !c.gone) {
+        let b = (a + 1..game.cards.len())
+            .find(|i| game.cards[*i].img_path == game.cards[a].img_path)
+            .unwrap();
+        game.pick_card(a, token.clone()).await.unwrap();
+        game.pick_card(b, token.clone()).await.unwrap();
+        while let Ok(event) = receiver.try_recv() {
+            if event.name == "finalBoard" {
+                boards.push(event.data);
+            }
+        }
+    }
+
+    assert_eq!(boards.len(), 1);
+    let cards = boards[0]["cards"].as_array().unwrap();
+    assert_eq!(cards.len(), game.cards.len());
+    for (card, sent) in game.cards.iter().zip(cards) {
+        assert_eq!(sent["img_path"], card.img_path.as_str());
+    }
+}
+
+#[test]
+fn rate_limits_refill_per_address() {
+    let mut store = MemoryStore {
+        rate_limit: RateLimit {
+            burst: 2,
+            interval: Duration::from_secs(10),
+        },
+        ..Default::default()
+    };
+    let (a, b) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+    let now = Instant::now();
+
+    assert!(store.take_token(a, now));
+    assert!(store.take_token(a, now));
+    assert!(!store.take_token(a, now));
+    assert!(store.take_token(b, now));
+    assert!(!store.take_token(a, now + Duration::from_secs(9)));
+    assert!(store.take_token(a, now + Duration::from_secs(10)));
+}
+
+#[tokio::test]
+async fn game_over_reports_how_long_the_game_ran() {
+    let mut game = new_game();
+    let alice = game.add_new_player("alice".to_owned(), None).unwrap();
+    game.add_new_player("bob".to_owned(), None).unwrap();
+    let (sender, mut receiver) = tokio::sync::mpsc::channel(256);
+    game.players.get_mut(&alice).unwrap().add_sender(sender);
+    assert_eq!(game.started_at, None);
+    game.start().await;
+    let token = game.players.iter().find(|(_, p)| p.turn).unwrap().0.clone();
+
+    // Pretend the game started 4m12s ago.
+    game.started_at = Some(unix_timestamp() - 252);
+    let restored: Memory = serde_json::from_str(&serde_json::to_string(&game).unwrap()).unwrap();
+    assert_eq!(restored.started_at, game.started_at);
+
+    while let Some(a) = game.cards.iter().position(|c| !c.gone) {
+        let b = (a + 1..game.cards.len())
+            .find(|i| game.cards[*i].img_path == game.cards[a].img_path)
+            .unwrap();
+        game.pick_card(a, token.clone()).await.unwrap();
+        game.pick_card(b, token.clone()).await.unwrap();
+    }
+    let mut duration = None;
+    while let Ok(event) = receiver.try_recv() {
+        if event.name == "gameOver" {
+            duration = event.data["duration_secs"].as_u64();
+        }
+    }
+    let duration = duration.expect("no gameOver event");
+    assert!((252..=253).contains(&duration));
+    assert_eq!(game.duration_secs, duration);
+
+    game.reset();
+    assert_eq!((game.started_at, game.duration_secs), (None, 0));
+}
+
+#[tokio::test]
+async fn slower_players_get_mismatched_cards_turned_back_later() {
+    let mut game = new_game();
+    let alice = game.add_new_player("alice".to_owned(), None).unwrap();
+    let bob = game.add_new_player("bob".to_owned(), None).unwrap();
+    game.players.get_mut(&alice).unwrap().reveal_multiplier = 2.5;
+    let (alice_tx, mut alice_rx) = tokio::sync::mpsc::channel(256);
+    let (bob_tx, mut bob_rx) = tokio::sync::mpsc::channel(256);
+    game.players.get_mut(&alice).unwrap().add_sender(alice_tx);
+    game.players.get_mut(&bob).unwrap().add_sender(bob_tx);
+    game.start().await;
+    let token = game.players.iter().find(|(_, p)| p.turn).unwrap().0.clone();
+
+    let b = (1..game.cards.len())
+        .find(|i| game.cards[*i].img_path != game.cards[0].img_path)
+        .unwrap();
+    game.pick_card(0, token.clone()).await.unwrap();
+    game.pick_card(b, token).await.unwrap();
+    let delays = game.skip_turn().await;
+    assert_eq!(delays, [game.reveal_delay().mul_f64(1.5)]);
+
+    let unflipped = |rx: &mut tokio::sync::mpsc::Receiver<GameEvent>| {
+        let mut unflipped = Vec::new();
+        while let Ok(event) = rx.try_recv() {
+            if event.name == "unflipCard" {
+                unflipped.push((event.data["card_id"].as_u64().unwrap(), event.id));
+            }
+        }
+        unflipped
+    };
+    let bob_unflipped = unflipped(&mut bob_rx);
+    assert_eq!(bob_unflipped.len(), 2);
+    assert!(unflipped(&mut alice_rx).is_empty());
+
+    // A card picked again before the delay is over stays face up.
+    let turn = game.turn_number();
+    let next = game.players.iter().find(|(_, p)| p.turn).unwrap().0.clone();
+    game.pick_card(0, next).await.unwrap();
+    game.release_unflips(turn, delays[0]).await;
+    assert_eq!(unflipped(&mut alice_rx), bob_unflipped[1..]);
+    game.release_unflips(turn, delays[0]).await;
+    assert!(unflipped(&mut alice_rx).is_empty());
+}
+
+#[tokio::test]
+async fn held_back_cards_are_turned_back_once_the_turn_moves_on() {
+    let mut game = new_game();
+    let alice = game.add_new_player("alice".to_owned(), None).unwrap();
+    game.add_new_player("bob".to_owned(), None).unwrap();
+    game.players.get_mut(&alice).unwrap().reveal_multiplier = 3.0;
+    let (alice_tx, mut alice_rx) = tokio::sync::mpsc::channel(256);
+    game.players.get_mut(&alice).unwrap().add_sender(alice_tx);
+    game.start().await;
+    let mismatch = |game: &Memory| {
+        let first = (0..game.cards.len())
+            .find(|i| !game.cards[*i].gone)
+            .unwrap();
+        let second = (0..game.cards.len())
+            .find(|i| !game.cards[*i].gone && game.cards[*i].img_path != game.cards[first].img_path)
+            .unwrap();
+        let token = game.players.iter().find(|(_, p)| p.turn).unwrap().0.clone();
+        (first, second, token)
+    };
+
+    let (first, second, token) = mismatch(&game);
+    game.pick_card(first, token.clone()).await.unwrap();
+    game.pick_card(second, token).await.unwrap();
+    let delays = game.skip_turn().await;
+    let stale = game.turn_number();
+    while alice_rx.try_recv().is_ok() {}
+
+    // The next turn ends before the delay does, and takes the cards along.
+    game.skip_turn().await;
+    let mut unflipped = Vec::new();
+    while let Ok(event) = alice_rx.try_recv() {
+        if event.name == "unflipCard" {
+            unflipped.push(event.data["card_id"].as_u64().unwrap() as usize);
+        }
+    }
+    assert_eq!(unflipped, [first, second]);
+    game.release_unflips(stale, delays[0]).await;
+    assert!(alice_rx.try_recv().is_err());
+}
+
+#[test]
+fn card_faces_tell_local_images_from_external_ones() {
+    let external = CardFace::new("https://example.com/cat.png");
+    assert_eq!(external.img_path, "https://example.com/cat.png");
+    assert!(external.is_external);
+
+    for path in [
+        "cat.png",
+        "./images/cat.png",
+        "images/cat.png",
+        "/img/cat.png",
+    ] {
+        let local = CardFace::new(path);
+        assert_eq!(local.img_path, "/img/cat.png", "{}", path);
+        assert!(!local.is_external);
+    }
+}
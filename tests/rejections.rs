@@ -0,0 +1,127 @@
+use memory_backend::queries::GameQuery;
+use memory_backend::reject::*;
+use warp::http::StatusCode;
+use warp::{Filter, Rejection};
+
+fn status(err: Rejection) -> StatusCode {
+    describe(&err).0
+}
+
+#[test]
+fn custom_rejections_map_to_client_errors() {
+    let cases = [
+        (warp::reject::custom(InvalidToken), StatusCode::UNAUTHORIZED),
+        (
+            warp::reject::custom(InvalidMasterKey),
+            StatusCode::UNAUTHORIZED,
+        ),
+        (
+            warp::reject::custom(InvalidGameAdmin),
+            StatusCode::UNAUTHORIZED,
+        ),
+        (warp::reject::custom(AlreadyExists), StatusCode::CONFLICT),
+        (warp::reject::custom(NameTaken), StatusCode::CONFLICT),
+        (warp::reject::custom(NoGameExists), StatusCode::NOT_FOUND),
+        (warp::reject::custom(NotInLobby), StatusCode::CONFLICT),
+        (warp::reject::custom(GamePaused), StatusCode::CONFLICT),
+        (warp::reject::custom(AlreadyRunning), StatusCode::CONFLICT),
+        (warp::reject::custom(NotYetRunning), StatusCode::CONFLICT),
+        (warp::reject::custom(NotYourTurn), StatusCode::FORBIDDEN),
+        (warp::reject::custom(InvalidCard), StatusCode::BAD_REQUEST),
+        (warp::reject::custom(AlreadyFlipped), StatusCode::CONFLICT),
+        (warp::reject::custom(TooManyFlipped), StatusCode::CONFLICT),
+        (warp::reject::custom(GameFull), StatusCode::CONFLICT),
+        (warp::reject::custom(NotEnoughPlayers), StatusCode::CONFLICT),
+        (warp::reject::custom(PlayerNotFound), StatusCode::NOT_FOUND),
+        (
+            warp::reject::custom(InvalidMessage),
+            StatusCode::BAD_REQUEST,
+        ),
+        (warp::reject::custom(TooFast), StatusCode::TOO_MANY_REQUESTS),
+        (
+            warp::reject::custom(TooManyRequests),
+            StatusCode::TOO_MANY_REQUESTS,
+        ),
+        (warp::reject::custom(InvalidInput), StatusCode::BAD_REQUEST),
+        (
+            warp::reject::custom(BodyTooLarge),
+            StatusCode::PAYLOAD_TOO_LARGE,
+        ),
+        (warp::reject::custom(WeakKey), StatusCode::BAD_REQUEST),
+        (warp::reject::custom(NothingToUndo), StatusCode::CONFLICT),
+        (warp::reject::custom(UndoUsed), StatusCode::CONFLICT),
+        (warp::reject::custom(ResolvingMatch), StatusCode::CONFLICT),
+        (
+            warp::reject::custom(TooManyGames),
+            StatusCode::SERVICE_UNAVAILABLE,
+        ),
+        (warp::reject::custom(PeeksDisabled), StatusCode::FORBIDDEN),
+        (warp::reject::custom(NoPeeksLeft), StatusCode::CONFLICT),
+        (
+            warp::reject::custom(InvalidBoardSize),
+            StatusCode::BAD_REQUEST,
+        ),
+        (
+            warp::reject::custom(NotEnoughImages {
+                needed: 27,
+                supplied: 3,
+            }),
+            StatusCode::BAD_REQUEST,
+        ),
+        (
+            warp::reject::custom(InvalidImages { urls: Vec::new() }),
+            StatusCode::BAD_REQUEST,
+        ),
+    ];
+
+    for (err, expected) in cases {
+        assert_eq!(status(err), expected);
+    }
+}
+
+#[tokio::test]
+async fn wrong_method_is_405() {
+    let filter = warp::post().map(warp::reply);
+    let Err(err) = warp::test::request().method("GET").filter(&filter).await else {
+        panic!("GET was accepted");
+    };
+    assert_eq!(status(err), StatusCode::METHOD_NOT_ALLOWED);
+}
+
+#[tokio::test]
+async fn missing_cookie_is_401() {
+    let filter = warp::cookie::<String>("memory_token");
+    let Err(err) = warp::test::request().filter(&filter).await else {
+        panic!("request without cookies was accepted");
+    };
+    assert_eq!(status(err), StatusCode::UNAUTHORIZED);
+
+    let Err(err) = warp::test::request()
+        .header("cookie", "other=1")
+        .filter(&filter)
+        .await
+    else {
+        panic!("request without memory_token was accepted");
+    };
+    assert_eq!(status(err), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn malformed_query_is_400() {
+    let filter = warp::query::<GameQuery>();
+    let Err(err) = warp::test::request().path("/?game=1").filter(&filter).await else {
+        panic!("query without id was accepted");
+    };
+    assert_eq!(status(err), StatusCode::BAD_REQUEST);
+}
+
+#[test]
+fn unknown_rejections_are_internal_errors() {
+    #[derive(Debug)]
+    struct Unexpected;
+    impl warp::reject::Reject for Unexpected {}
+
+    let (status, code, _) = describe(&warp::reject::custom(Unexpected));
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(code, "INTERNAL_ERROR");
+}